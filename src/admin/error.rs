@@ -0,0 +1,36 @@
+use std::{error::Error as StdError, fmt, result::Result as StdResult};
+
+use crate::controller::database::error::Error as DBError;
+
+pub type Result<T> = StdResult<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Database(DBError),
+    Json(serde_json::Error),
+    UnknownFile(String),
+}
+
+impl StdError for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Database(err) => write!(f, "Database Error: {}", err),
+            Self::Json(err) => write!(f, "Failed to serialize output: {}", err),
+            Self::UnknownFile(path) => write!(f, "File not found in database: {}", path),
+        }
+    }
+}
+
+impl From<DBError> for Error {
+    fn from(err: DBError) -> Self {
+        Self::Database(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
@@ -0,0 +1,174 @@
+use std::path::Path;
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+pub mod error;
+mod table;
+
+use crate::admin::error::{Error, Result};
+use crate::admin::table::Table;
+use crate::config::OutputFormat;
+use crate::controller::database::{Database, DestinationRecord, FileEvent, FileFilter, FileRecord};
+
+static DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Prints file counts per state and the oldest file waiting for upload
+pub fn status(db: &Database, format: OutputFormat) -> Result<()> {
+    let summary = db.summary()?;
+
+    match format {
+        OutputFormat::Json => print_json(&summary)?,
+        OutputFormat::Table => {
+            println!("Total:\t\t{}", summary.total);
+            println!("Uploaded:\t{}", summary.uploaded);
+            println!("Pending:\t{}", summary.pending);
+            println!("Failed:\t\t{}", summary.failed);
+            println!("Deleted:\t{}", summary.deleted);
+            match summary.oldest_pending {
+                Some(file) => println!(
+                    "Oldest pending:\t{} (first seen {})",
+                    file.path,
+                    file.first_seen_date.format(DATE_FORMAT)
+                ),
+                None => println!("Oldest pending:\t-"),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prints the files matching the filter
+pub fn list(db: &Database, filter: &FileFilter, format: OutputFormat) -> Result<()> {
+    let files = db.list_files(filter)?;
+
+    match format {
+        OutputFormat::Json => print_json(&files)?,
+        OutputFormat::Table => {
+            let mut table = Table::new(&["PATH", "FIRST SEEN", "UPLOADED", "ATTEMPTS", "ERROR"]);
+            for file in files {
+                table.add_row(vec![
+                    file.path,
+                    format_date(Some(file.first_seen_date)),
+                    format_date(file.uploaded_date),
+                    file.attempts.to_string(),
+                    file.last_error.unwrap_or_else(|| "-".into()),
+                ]);
+            }
+            print!("{}", table);
+        }
+    }
+    Ok(())
+}
+
+/// Prints everything known about a single file
+///
+/// Files are recorded by their canonical path, so the given path is canonicalized if it still exists.
+pub fn show(db: &Database, path: &str, format: OutputFormat) -> Result<()> {
+    let path = Path::new(path)
+        .canonicalize()
+        .unwrap_or_else(|_| path.into());
+
+    let file = db
+        .get_file(&path)?
+        .ok_or_else(|| Error::UnknownFile(path.display().to_string()))?;
+    let destinations = db.file_destinations(&path)?;
+    let history = db.file_events(&path)?;
+
+    match format {
+        OutputFormat::Json => {
+            #[derive(Serialize)]
+            struct FileDetails {
+                #[serde(flatten)]
+                file: FileRecord,
+                destinations: Vec<DestinationRecord>,
+                history: Vec<FileEvent>,
+            }
+            print_json(&FileDetails {
+                file,
+                destinations,
+                history,
+            })?
+        }
+        OutputFormat::Table => {
+            println!("Path:\t\t{}", file.path);
+            println!("First seen:\t{}", format_date(Some(file.first_seen_date)));
+            println!("Uploaded:\t{}", format_date(file.uploaded_date));
+            println!("Failed:\t\t{}", format_date(file.failed_date));
+            println!("Deleted:\t{}", format_date(file.deleted_date));
+            println!("Attempts:\t{}", file.attempts);
+            println!("Last error:\t{}", file.last_error.as_deref().unwrap_or("-"));
+            println!();
+
+            if !destinations.is_empty() {
+                let mut table = Table::new(&[
+                    "DESTINATION",
+                    "UPLOADED",
+                    "FAILED",
+                    "ATTEMPTS",
+                    "LAST ERROR",
+                ]);
+                for destination in destinations {
+                    table.add_row(vec![
+                        destination.destination,
+                        format_date(destination.uploaded_date),
+                        format_date(destination.failed_date),
+                        destination.attempts.to_string(),
+                        destination.last_error.unwrap_or_else(|| "-".into()),
+                    ]);
+                }
+                println!("{}", table);
+            }
+
+            let mut table = Table::new(&["DATE", "EVENT", "DETAIL"]);
+            for event in history {
+                table.add_row(vec![
+                    format_date(Some(event.date)),
+                    event.kind,
+                    event.detail.unwrap_or_default(),
+                ]);
+            }
+            print!("{}", table);
+        }
+    }
+    Ok(())
+}
+
+/// Marks the files matching the filter to be uploaded again on the next run
+pub fn requeue(db: &Database, filter: &FileFilter, dry_run: bool) -> Result<()> {
+    if dry_run {
+        return print_dry_run(db, filter, "requeue");
+    }
+    let count = db.requeue(filter)?;
+    println!("Requeued {} file(s)", count);
+    Ok(())
+}
+
+/// Removes the files matching the filter from the database
+pub fn forget(db: &Database, filter: &FileFilter, dry_run: bool) -> Result<()> {
+    if dry_run {
+        return print_dry_run(db, filter, "forget");
+    }
+    let count = db.forget(filter)?;
+    println!("Forgot {} file(s)", count);
+    Ok(())
+}
+
+fn print_dry_run(db: &Database, filter: &FileFilter, action: &str) -> Result<()> {
+    let files = db.list_files(filter)?;
+    for file in &files {
+        println!("Would {} {}", action, file.path);
+    }
+    println!("Would {} {} file(s)", action, files.len());
+    Ok(())
+}
+
+fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn format_date(date: Option<NaiveDateTime>) -> String {
+    date.map(|d| d.format(DATE_FORMAT).to_string())
+        .unwrap_or_else(|| "-".into())
+}
@@ -0,0 +1,57 @@
+use std::fmt;
+
+/// A plain text table, with columns padded to their widest cell
+pub struct Table {
+    headers: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(headers: &[&str]) -> Self {
+        Self {
+            headers: headers.iter().map(|&h| h.into()).collect(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn add_row(&mut self, row: Vec<String>) {
+        self.rows.push(row);
+    }
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut widths: Vec<usize> = self.headers.iter().map(|h| h.chars().count()).collect();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        for row in std::iter::once(&self.headers).chain(&self.rows) {
+            let line = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, &width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ");
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Table;
+
+    #[test]
+    fn test_columns_are_aligned() {
+        let mut table = Table::new(&["PATH", "ATTEMPTS"]);
+        table.add_row(vec!["/a/long/path".into(), "1".into()]);
+        table.add_row(vec!["/b".into(), "12".into()]);
+
+        let expected = "PATH          ATTEMPTS\n/a/long/path  1\n/b            12\n";
+        assert_eq!(table.to_string(), expected);
+    }
+}
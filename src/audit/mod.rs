@@ -0,0 +1,154 @@
+//! Append-only trail of the uploads
+//!
+//! Each upload, successful or not, is recorded as one JSON object per line.
+//! Unlike the logs, this file is never rotated nor filtered by level.
+
+use std::fs::{File as FSFile, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::controller::file::File;
+use crate::uploader::error::ErrorKind;
+use crate::uploader::Outcome;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    Uploaded,
+    Failed,
+    /// The file reached all its destinations, only notified
+    Synced,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Entry<'a> {
+    pub event: Event,
+    pub path: String,
+    /// Name of the destination, `default` unless the directory has several
+    pub destination: &'a str,
+    pub bucket: &'a str,
+    pub key: String,
+    /// Only known for completed uploads
+    pub size: Option<u64>,
+    /// MD5 of the whole file, in hex
+    pub md5: Option<&'a str>,
+    pub e_tag: Option<&'a str>,
+    /// Only set when the bucket is versioned
+    pub version_id: Option<&'a str>,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub uploader: &'a str,
+    pub error: Option<String>,
+    pub error_kind: Option<ErrorKind>,
+}
+
+impl<'a> Entry<'a> {
+    pub fn new(file: &File, outcome: &'a Outcome) -> Self {
+        let (event, uploaded, error, error_kind) = match &outcome.result {
+            Ok(uploaded) => (Event::Uploaded, Some(uploaded), None, None),
+            Err(err) => (Event::Failed, None, Some(err.to_string()), Some(err.kind())),
+        };
+
+        Self {
+            event,
+            path: file.full_path.to_string_lossy().into(),
+            destination: &outcome.destination,
+            bucket: &outcome.bucket,
+            key: file.key.clone(),
+            size: uploaded.map(|u| u.size),
+            md5: uploaded.map(|u| u.md5.as_str()),
+            e_tag: uploaded.and_then(|u| u.e_tag.as_deref()),
+            version_id: uploaded.and_then(|u| u.version_id.as_deref()),
+            started: outcome.started,
+            finished: outcome.finished,
+            uploader: &outcome.uploader,
+            error,
+            error_kind,
+        }
+    }
+}
+
+pub struct AuditLog {
+    file: FSFile,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    /// Appends an entry and makes sure it reached the disk
+    pub fn record(&self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        // A single write, so that a line is never interleaved with another
+        (&self.file).write_all(&line)?;
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuditLog, Entry};
+    use crate::controller::file::File;
+    use crate::uploader::{Outcome, Uploaded};
+    use chrono::Utc;
+    use serde_json::Value;
+    use std::fs;
+
+    #[test]
+    fn test_record_appends_one_line_per_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let file = File {
+            full_path: "/data/dir/a.txt".into(),
+            key: "dir/a.txt".into(),
+            uploaded_to: Vec::new(),
+        };
+        let outcome = |result| Outcome {
+            uploader: "uploader 1".into(),
+            destination: "default".into(),
+            bucket: "bucket".into(),
+            started: Utc::now(),
+            finished: Utc::now(),
+            result,
+        };
+        let uploaded = outcome(Ok(Uploaded {
+            size: 3,
+            md5: "900150983cd24fb0d6963f7d28e17f72".into(),
+            e_tag: Some("\"etag\"".into()),
+            version_id: None,
+        }));
+        let failed = outcome(Err("Didn't get an upload_id".into()));
+
+        let audit = AuditLog::open(&path).unwrap();
+        audit.record(&Entry::new(&file, &uploaded)).unwrap();
+        drop(audit);
+        let audit = AuditLog::open(&path).unwrap();
+        audit.record(&Entry::new(&file, &failed)).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "uploaded");
+        assert_eq!(lines[0]["key"], "dir/a.txt");
+        assert_eq!(lines[0]["destination"], "default");
+        assert_eq!(lines[0]["size"], 3);
+        assert_eq!(lines[0]["uploader"], "uploader 1");
+        assert_eq!(lines[0]["version_id"], Value::Null);
+        assert_eq!(lines[1]["event"], "failed");
+        assert_eq!(lines[1]["md5"], Value::Null);
+        assert_eq!(
+            lines[1]["error"],
+            "Failed to upload file: Didn't get an upload_id"
+        );
+        assert_eq!(lines[1]["error_kind"], "transient");
+    }
+}
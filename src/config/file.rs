@@ -0,0 +1,405 @@
+//! Settings read from the TOML configuration file
+//!
+//! ```toml
+//! [[watch]]
+//! dir = "/data/reports"
+//! storage_class = "STANDARD_IA"
+//! guess_content_type = true
+//! cache_control = "max-age=86400"
+//! priority = 1
+//!
+//! [watch.tags]
+//! source = "{host}"
+//!
+//! [watch.metadata]
+//! original-mtime = "{mtime}"
+//!
+//! [watch.credentials]
+//! profile = "reports"
+//! role_arn = "arn:aws:iam::123456789012:role/reports-uploader"
+//!
+//! [[watch]]
+//! dir = "/data/invoices"
+//! destinations = ["default", "dr"]
+//!
+//! [[destination]]
+//! name = "dr"
+//! bucket = "invoices-dr"
+//! region = "eu-central-1"
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rusoto_core::Region;
+use serde::Deserialize;
+
+use crate::config::{is_endpoint_url, Destination, DEFAULT_DESTINATION, DEFAULT_REGION};
+use crate::scheduler::QueueSettings;
+use crate::template::Template;
+use crate::uploader::credentials::{AssumeRole, Credentials};
+use crate::uploader::rules::{UploadRules, MAX_TAGS, PLACEHOLDERS, STORAGE_CLASSES};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    watch: Vec<WatchEntry>,
+    #[serde(default)]
+    destination: Vec<DestinationEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WatchEntry {
+    dir: String,
+    storage_class: Option<String>,
+    content_type: Option<String>,
+    #[serde(default)]
+    guess_content_type: bool,
+    cache_control: Option<String>,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+    credentials: Option<CredentialsEntry>,
+    #[serde(default)]
+    priority: u32,
+    weight: Option<u32>,
+    /// Names of the destinations, the one given on the command line being `default`
+    destinations: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DestinationEntry {
+    name: String,
+    bucket: Option<String>,
+    region: Option<String>,
+    endpoint_url: Option<String>,
+    dir: Option<PathBuf>,
+}
+
+impl DestinationEntry {
+    fn into_destination(self) -> Result<Destination, String> {
+        match (self.bucket, self.dir) {
+            (Some(bucket_name), None) => {
+                let name = self.region.unwrap_or_else(|| DEFAULT_REGION.into());
+                let region = match self.endpoint_url {
+                    Some(endpoint) => {
+                        is_endpoint_url(endpoint.clone())?;
+                        Region::Custom { name, endpoint }
+                    }
+                    None => name
+                        .parse()
+                        .map_err(|_| format!("Unknown region {}", name))?,
+                };
+                Ok(Destination::S3 {
+                    bucket_name,
+                    region,
+                })
+            }
+            (None, Some(_)) if self.region.is_some() || self.endpoint_url.is_some() => {
+                Err("region and endpoint_url only apply to buckets".into())
+            }
+            (None, Some(dir)) if !dir.is_dir() => {
+                Err(format!("{} is not a directory", dir.display()))
+            }
+            (None, Some(dir)) => Ok(Destination::Directory(dir)),
+            _ => Err("Expected either bucket or dir".into()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CredentialsEntry {
+    profile: Option<String>,
+    keys_file: Option<PathBuf>,
+    role_arn: Option<String>,
+    external_id: Option<String>,
+    session_name: Option<String>,
+}
+
+impl CredentialsEntry {
+    fn into_credentials(self) -> Result<Credentials, String> {
+        let assume_role = match self.role_arn {
+            Some(role_arn) => Some(AssumeRole::new(
+                &role_arn,
+                self.external_id.as_deref(),
+                self.session_name.as_deref(),
+            )?),
+            None if self.external_id.is_some() || self.session_name.is_some() => {
+                return Err("external_id and session_name require role_arn".into())
+            }
+            None => None,
+        };
+        Credentials::new(
+            self.profile.as_deref(),
+            self.keys_file.as_deref(),
+            assume_role,
+        )
+    }
+}
+
+/// What the configuration file sets
+#[derive(Default)]
+pub struct Settings {
+    pub watch_dirs: Vec<WatchDir>,
+    /// Destinations the watched directories can upload to, besides the default one
+    pub destinations: Vec<(String, Destination)>,
+}
+
+/// A directory to watch, as configured in the file
+pub struct WatchDir {
+    pub dir: String,
+    pub rules: UploadRules,
+    pub queue: QueueSettings,
+}
+
+pub fn load(path: &Path) -> Result<Settings, String> {
+    let content = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    parse(&content)
+}
+
+fn parse(content: &str) -> Result<Settings, String> {
+    let config: ConfigFile = toml::from_str(content).map_err(|err| err.to_string())?;
+
+    let mut destinations: Vec<(String, Destination)> = Vec::new();
+    for entry in config.destination {
+        let name = entry.name.clone();
+        if name == DEFAULT_DESTINATION {
+            return Err(format!(
+                "The destination name {} is reserved for the one given on the command line",
+                name
+            ));
+        }
+        if destinations.iter().any(|(known, _)| *known == name) {
+            return Err(format!("Destination {} is defined twice", name));
+        }
+        let destination = entry
+            .into_destination()
+            .map_err(|err| format!("In destination {}: {}", name, err))?;
+        destinations.push((name, destination));
+    }
+
+    let is_known = |name: &String| {
+        name == DEFAULT_DESTINATION || destinations.iter().any(|(known, _)| known == name)
+    };
+    let watch_dirs = config
+        .watch
+        .into_iter()
+        .map(|entry| {
+            let dir = entry.dir.clone();
+            if let Some(name) = entry
+                .destinations
+                .iter()
+                .flatten()
+                .find(|name| !is_known(name))
+            {
+                return Err(format!(
+                    "In watch dir {}: unknown destination {}",
+                    dir, name
+                ));
+            }
+            entry
+                .into_watch_dir()
+                .map_err(|err| format!("In watch dir {}: {}", dir, err))
+        })
+        .collect::<Result<_, String>>()?;
+
+    Ok(Settings {
+        watch_dirs,
+        destinations,
+    })
+}
+
+impl WatchEntry {
+    fn into_watch_dir(self) -> Result<WatchDir, String> {
+        if let Some(class) = &self.storage_class {
+            if !STORAGE_CLASSES.contains(&class.as_str()) {
+                return Err(format!(
+                    "Unknown storage class {}, expected one of: {}",
+                    class,
+                    STORAGE_CLASSES.join(", ")
+                ));
+            }
+        }
+        if self.content_type.is_some() && self.guess_content_type {
+            return Err("content_type and guess_content_type can't be used together".into());
+        }
+        if self.tags.len() > MAX_TAGS {
+            return Err(format!("At most {} tags can be set", MAX_TAGS));
+        }
+        if let Some(name) = self.metadata.keys().find(|name| !is_metadata_name(name)) {
+            return Err(format!(
+                "Invalid metadata name {}, only lowercase letters, digits, - and _ are allowed",
+                name
+            ));
+        }
+
+        if self.weight == Some(0) {
+            return Err("weight must be at least 1".into());
+        }
+        if self.destinations.as_ref().is_some_and(Vec::is_empty) {
+            return Err("destinations can't be empty".into());
+        }
+        let destinations = self.destinations.unwrap_or_default();
+        if let Some(name) = destinations
+            .iter()
+            .enumerate()
+            .find_map(|(index, name)| destinations[..index].contains(name).then_some(name))
+        {
+            return Err(format!("Destination {} is listed twice", name));
+        }
+
+        let templates = |entries: BTreeMap<String, String>| {
+            entries
+                .into_iter()
+                .map(|(name, value)| Ok((name, Template::parse(&value, PLACEHOLDERS)?)))
+                .collect::<Result<Vec<_>, String>>()
+        };
+
+        Ok(WatchDir {
+            dir: self.dir,
+            rules: UploadRules {
+                storage_class: self.storage_class,
+                content_type: self.content_type,
+                guess_content_type: self.guess_content_type,
+                cache_control: self.cache_control,
+                tags: templates(self.tags)?,
+                metadata: templates(self.metadata)?,
+                credentials: self
+                    .credentials
+                    .map(CredentialsEntry::into_credentials)
+                    .transpose()?,
+                destinations,
+            },
+            queue: QueueSettings {
+                priority: self.priority,
+                weight: self.weight.unwrap_or(1),
+            },
+        })
+    }
+}
+
+/// Metadata names end up in HTTP headers, which S3 lowercases
+fn is_metadata_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+    use crate::config::Destination;
+    use crate::scheduler::QueueSettings;
+
+    #[test]
+    fn test_parse_watch_dirs() {
+        let watch_dirs = parse(
+            r#"
+            [[watch]]
+            dir = "/data/reports"
+            storage_class = "STANDARD_IA"
+            guess_content_type = true
+            tags = { source = "{host}" }
+
+            [[watch]]
+            dir = "/data/logs"
+            credentials = { profile = "logs" }
+            priority = 1
+            weight = 3
+            "#,
+        )
+        .unwrap()
+        .watch_dirs;
+
+        assert_eq!(watch_dirs.len(), 2);
+        assert_eq!(watch_dirs[0].dir, "/data/reports");
+        assert_eq!(
+            watch_dirs[0].rules.storage_class.as_deref(),
+            Some("STANDARD_IA")
+        );
+        assert_eq!(watch_dirs[0].rules.tags[0].0, "source");
+        assert!(watch_dirs[1].rules.tags.is_empty());
+        assert!(watch_dirs[0].rules.credentials.is_none());
+        assert!(watch_dirs[1].rules.credentials.is_some());
+        assert_eq!(watch_dirs[0].queue, QueueSettings::default());
+        assert_eq!(
+            watch_dirs[1].queue,
+            QueueSettings {
+                priority: 1,
+                weight: 3
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_rules() {
+        for content in &[
+            "[[watch]]\ndir = \"/a\"\nstorage_class = \"COLD\"",
+            "[[watch]]\ndir = \"/a\"\ntags = { a = \"{unknown}\" }",
+            "[[watch]]\ndir = \"/a\"\nmetadata = { \"Bad Name\" = \"x\" }",
+            "[[watch]]\ndir = \"/a\"\nstorage = \"STANDARD\"",
+            "[[watch]]\ndir = \"/a\"\ncontent_type = \"text/csv\"\nguess_content_type = true",
+            "[[watch]]\ndir = \"/a\"\ncredentials = { external_id = \"x\" }",
+            "[[watch]]\ndir = \"/a\"\ncredentials = { role_arn = \"uploader\" }",
+            "[[watch]]\ndir = \"/a\"\nweight = 0",
+            "[[watch]]\ndir = \"/a\"\ndestinations = []",
+            "[[watch]]\ndir = \"/a\"\ndestinations = [\"dr\"]",
+            "[[watch]]\ndir = \"/a\"\ndestinations = [\"default\", \"default\"]",
+            "[[destination]]\nname = \"default\"\nbucket = \"b\"",
+            "[[destination]]\nname = \"dr\"",
+            "[[destination]]\nname = \"dr\"\nbucket = \"b\"\nregion = \"mars-1\"",
+            "[[destination]]\nname = \"dr\"\ndir = \"/nonexistent\"",
+        ] {
+            assert!(parse(content).is_err(), "{}", content);
+        }
+    }
+
+    #[test]
+    fn test_parse_destinations() {
+        let settings = parse(
+            r#"
+            [[watch]]
+            dir = "/data/invoices"
+            destinations = ["default", "dr", "share"]
+
+            [[watch]]
+            dir = "/data/logs"
+
+            [[destination]]
+            name = "dr"
+            bucket = "invoices-dr"
+            region = "eu-central-1"
+
+            [[destination]]
+            name = "share"
+            dir = "/"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            settings.watch_dirs[0].rules.destinations,
+            vec!["default", "dr", "share"]
+        );
+        assert!(settings.watch_dirs[1].rules.destinations.is_empty());
+        let names: Vec<_> = settings.destinations.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["dr", "share"]);
+        match &settings.destinations[0].1 {
+            Destination::S3 {
+                bucket_name,
+                region,
+            } => {
+                assert_eq!(bucket_name, "invoices-dr");
+                assert_eq!(region.name(), "eu-central-1");
+            }
+            destination => panic!("Unexpected destination {}", destination),
+        }
+    }
+}
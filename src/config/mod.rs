@@ -1,114 +1,677 @@
-use clap::{App, AppSettings, Arg};
+use std::ffi::OsString;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 
+use chrono::{NaiveDate, NaiveDateTime};
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, ErrorKind, SubCommand};
+use log::LevelFilter;
+use rusoto_core::Region;
+
+use crate::controller::database::{FileFilter, FileStatus};
+use crate::controller::file::{KeyDate, KeyTemplate, DEFAULT_KEY_TEMPLATE};
+use crate::controller::key::{KeyPolicy, NonUtf8Policy, UnsafeCharPolicy, MAX_KEY_LENGTH};
+use crate::logging::{LogConfig, LogFormat, Rotation};
+use crate::notifier::WebhookConfig;
+use crate::scheduler::QueueSettings;
+use crate::throttle::{self, Schedule, Throttle, Window};
+use crate::uploader::credentials::{self, AssumeRole, Credentials, StaticKeys};
+use crate::uploader::cse::ClientSideEncryption;
+use crate::uploader::rules::RuleSet;
+use crate::uploader::sse::ServerSideEncryption;
+
+pub mod file;
+
+/// Name of the destination given on the command line
+pub const DEFAULT_DESTINATION: &str = "default";
+static DEFAULT_REGION: &str = "eu-west-3";
+static DEFAULT_DATABASE_PATH: &str = "db.sqlite3";
+static DEFAULT_LOG_LEVEL: &str = "info";
+static DEFAULT_LOG_KEEP: &str = "7";
+/// Modules of this program, which can be given to `--log-module` without the crate prefix
+static OWN_MODULES: &[&str] = &[
+    "admin",
+    "audit",
+    "config",
+    "controller",
+    "health",
+    "logging",
+    "metrics",
+    "notifier",
+    "scheduler",
+    "server",
+    "supervisor",
+    "template",
+    "throttle",
+    "uploader",
+    "watcher",
+];
+static DEFAULT_WEBHOOK_BATCH_SIZE: &str = "10";
+static DEFAULT_WEBHOOK_ATTEMPTS: &str = "5";
 static DEFAULT_UPLOAD_SIZE: u64 = 100;
 static MAX_UPLOAD_SIZE: u64 = 1000;
 static MIN_UPLOAD_SIZE: u64 = 10;
 static DEFAULT_NUM_UPLOADERS: u64 = 2;
+static DEFAULT_QUEUE_WINDOW: &str = "100";
 static DEFAULT_WATCHER_INTERVAL: u64 = 2;
 static MIN_WATCHER_INTERVAL: u64 = 1;
 
+/// What the program was asked to do
+pub enum Command {
+    /// Watch directories and upload new files as they appear
+    Watch(SyncConfig),
+    /// Upload the files of the directories that haven't been uploaded yet, then exit
+    Sync(SyncConfig),
+    /// Print file counts per state
+    Status { format: OutputFormat },
+    /// Print the files matching a filter
+    List {
+        filter: FileFilter,
+        format: OutputFormat,
+    },
+    /// Print a file's record and history
+    Show { path: String, format: OutputFormat },
+    /// Mark the files matching a filter for upload on the next run
+    Requeue { filter: FileFilter, dry_run: bool },
+    /// Remove the files matching a filter from the database
+    Forget { filter: FileFilter, dry_run: bool },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
 pub struct Config {
+    pub database_path: String,
+    pub log: LogConfig,
+    pub command: Command,
+}
+
+/// Where the files are uploaded
+#[derive(Clone, Debug)]
+pub enum Destination {
+    S3 {
+        bucket_name: String,
+        /// Custom when an endpoint URL is given
+        region: Region,
+    },
+    /// A directory, possibly a network share, receiving a copy of each file at its key
+    Directory(PathBuf),
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::S3 { bucket_name, .. } => write!(f, "s3://{}", bucket_name),
+            Self::Directory(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SyncConfig {
     pub watched_dirs: Vec<String>,
-    pub bucket_name: String,
+    /// Attributes of the objects, per watched directory
+    pub rules: RuleSet,
+    /// The default destination comes first, if one was given on the command line
+    pub destinations: Vec<(String, Destination)>,
+    /// Used for the directories that don't have credentials of their own
+    pub credentials: Credentials,
+    /// Where files are uploaded on the bucket
+    pub key_template: KeyTemplate,
     pub num_uploaders: u64,
     pub upload_part_size: u64,
+    /// Shared by all the uploaders, `None` when uploads aren't limited
+    pub throttle: Option<Arc<Throttle>>,
+    /// Scheduling of the files, per watched directory
+    pub queues: Vec<(PathBuf, QueueSettings)>,
+    /// Files queued or being uploaded at most
+    pub queue_window: usize,
+    pub smallest_first: bool,
     pub watcher_delay: u64,
+    /// Address of the HTTP server exposing metrics
+    pub listen_address: Option<String>,
+    /// Only log what would be uploaded, without calling S3 or writing to the database
+    pub dry_run: bool,
+    /// File recording every upload as a JSON line
+    pub audit_log: Option<PathBuf>,
+    pub webhook: Option<WebhookConfig>,
+    pub sse: ServerSideEncryption,
+    /// Encryption of the files before they're uploaded
+    pub cse: Option<ClientSideEncryption>,
 }
 
 impl Config {
     pub fn from_args() -> Self {
+        Self::from_iter(std::env::args_os())
+    }
+
+    /// Parses the arguments, the first one being the program name
+    pub fn from_iter<I, T>(args: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
         let upload_size_default = format!("{}", DEFAULT_UPLOAD_SIZE);
         let watcher_interval_default = format!("{}", DEFAULT_WATCHER_INTERVAL);
         let uploader_threads_default = format!("{}", DEFAULT_NUM_UPLOADERS);
+        let key_max_length_default = format!("{}", MAX_KEY_LENGTH);
+        let upload_size_help = format!(
+            "Upload part size in MB. Must be between {} and {}",
+            MIN_UPLOAD_SIZE, MAX_UPLOAD_SIZE
+        );
+        let watch_interval_help = format!(
+            "File change notifications interval. Must be at least {}",
+            MIN_WATCHER_INTERVAL
+        );
+
+        // Shared by the default watch mode and the one-shot sync
+        let sync_args = [
+            Arg::with_name("watch_dir")
+                .short("w")
+                .long("watch-dir")
+                .value_name("DIR")
+                .help("Directories to sync")
+                .takes_value(true)
+                .required_unless("config")
+                .min_values(1)
+                .multiple(true),
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("TOML file listing directories to sync, with the attributes of their objects")
+                .takes_value(true)
+                .validator(is_config_file),
+            Arg::with_name("bucket_name")
+                .short("b")
+                .long("bucket")
+                .value_name("BUCKET")
+                .help("AWS bucket name")
+                .takes_value(true)
+                .required_unless_one(&["destination_dir", "config"]),
+            Arg::with_name("destination_dir")
+                .long("destination-dir")
+                .value_name("DIR")
+                .help("Copy files under DIR, at their key, instead of uploading them to S3. DIR can be an NFS or SMB mount")
+                .takes_value(true)
+                .conflicts_with("bucket_name")
+                .validator(is_dir),
+            Arg::with_name("region")
+                .long("region")
+                .value_name("REGION")
+                .help("AWS region of the bucket")
+                .takes_value(true)
+                .default_value(DEFAULT_REGION),
+            Arg::with_name("endpoint_url")
+                .long("endpoint-url")
+                .value_name("URL")
+                .help("Send S3 requests to this endpoint instead of AWS, ex: http://localhost:9000")
+                .takes_value(true)
+                .validator(is_endpoint_url),
+            Arg::with_name("aws_profile")
+                .long("aws-profile")
+                .value_name("NAME")
+                .help("Use this profile of the AWS credentials file instead of the default credential chain")
+                .takes_value(true),
+            Arg::with_name("aws_keys_file")
+                .long("aws-keys-file")
+                .value_name("FILE")
+                .help("Use the keys of this TOML file, with access_key_id, secret_access_key and optionally session_token")
+                .takes_value(true)
+                .conflicts_with("aws_profile")
+                .validator(is_keys_file),
+            Arg::with_name("assume_role")
+                .long("assume-role")
+                .value_name("ARN")
+                .help("Assume this role with STS, the credentials being refreshed before they expire")
+                .takes_value(true)
+                .validator(is_role_arn),
+            Arg::with_name("assume_role_external_id")
+                .long("assume-role-external-id")
+                .value_name("ID")
+                .help("External ID required by the trust policy of the role")
+                .takes_value(true)
+                .requires("assume_role"),
+            Arg::with_name("assume_role_session_name")
+                .long("assume-role-session-name")
+                .value_name("NAME")
+                .help("Session name of the assumed role, shown in CloudTrail. Defaults to s3-file-sync")
+                .takes_value(true)
+                .requires("assume_role")
+                .validator(is_session_name),
+            Arg::with_name("key_template")
+                .long("key-template")
+                .value_name("TEMPLATE")
+                .help("Key of the objects, with placeholders among {prefix}, {relative_path}, {dir}, {filename}, {yyyy}, {mm}, {dd}, {hh} and {host}")
+                .takes_value(true)
+                .default_value(DEFAULT_KEY_TEMPLATE)
+                .validator(is_key_template),
+            Arg::with_name("key_date")
+                .long("key-date")
+                .value_name("DATE")
+                .help("Date filling the date placeholders of keys: the file's modification time or when it was detected")
+                .takes_value(true)
+                .possible_values(&["mtime", "detected"])
+                .default_value("mtime"),
+            Arg::with_name("key_nfc")
+                .long("key-nfc")
+                .help("Normalize keys to Unicode NFC"),
+            Arg::with_name("key_non_utf8")
+                .long("key-non-utf8")
+                .value_name("POLICY")
+                .help("What to do with names that aren't valid UTF-8: percent-encode the invalid bytes, replace them, or skip the file")
+                .takes_value(true)
+                .possible_values(&["escape", "lossy", "reject"])
+                .default_value("escape"),
+            Arg::with_name("key_unsafe_chars")
+                .long("key-unsafe-chars")
+                .value_name("POLICY")
+                .help("What to do with control characters and \\{}^%`[]\"<>~#| in names: keep them, percent-encode them, or skip the file")
+                .takes_value(true)
+                .possible_values(&["keep", "escape", "reject"])
+                .default_value("keep"),
+            Arg::with_name("key_max_length")
+                .long("key-max-length")
+                .value_name("BYTES")
+                .help("Skip files whose key would be longer")
+                .takes_value(true)
+                .default_value(&key_max_length_default)
+                .validator(key_length_within_bounds),
+            Arg::with_name("upload_size")
+                .short("s")
+                .long("upload-part-size")
+                .value_name("SIZE")
+                .help(&upload_size_help)
+                .takes_value(true)
+                .required(false)
+                .default_value(&upload_size_default)
+                .validator(upload_size_between_bounds),
+            Arg::with_name("uploader_threads")
+                .short("u")
+                .long("uploader-threads")
+                .value_name("NUM")
+                .help("Number of uploader threads")
+                .takes_value(true)
+                .required(false)
+                .default_value(&uploader_threads_default)
+                .validator(int_gte_1),
+            Arg::with_name("bandwidth_limit")
+                .long("bandwidth-limit")
+                .value_name("RATE")
+                .help("Limit uploads to RATE bytes per second, with an optional K, M or G unit, ex: 5M")
+                .takes_value(true)
+                .validator(is_rate),
+            Arg::with_name("bandwidth_schedule")
+                .long("bandwidth-schedule")
+                .value_name("WINDOW")
+                .help("Limit uploads to another rate during a time of day, ex: 08:00-19:00=5M. Can be repeated, the first matching window wins")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(is_bandwidth_window),
+            Arg::with_name("queue_window")
+                .long("queue-window")
+                .value_name("NUM")
+                .help("Number of files handed to the uploaders at a time, the others wait in the database")
+                .takes_value(true)
+                .default_value(DEFAULT_QUEUE_WINDOW)
+                .validator(int_gte_1),
+            Arg::with_name("smallest_first")
+                .long("smallest-first")
+                .help("Upload the smallest waiting file of a directory first, instead of the oldest"),
+            Arg::with_name("listen")
+                .long("listen")
+                .value_name("ADDRESS")
+                .help("Serve Prometheus metrics on /metrics and liveness on /healthz, ex: 0.0.0.0:9100")
+                .takes_value(true)
+                .validator(is_socket_address),
+            Arg::with_name("audit_log")
+                .long("audit-log")
+                .value_name("FILE")
+                .help("Append a JSON line to FILE for every completed or failed upload")
+                .takes_value(true),
+            Arg::with_name("webhook_url")
+                .long("webhook-url")
+                .value_name("URL")
+                .help("POST a JSON notification to URL when a file is uploaded or fails to upload")
+                .takes_value(true)
+                .validator(is_http_url),
+            Arg::with_name("webhook_batch_size")
+                .long("webhook-batch-size")
+                .value_name("NUM")
+                .help("Maximum number of notifications sent in one request")
+                .takes_value(true)
+                .default_value(DEFAULT_WEBHOOK_BATCH_SIZE)
+                .validator(int_gte_1),
+            Arg::with_name("webhook_attempts")
+                .long("webhook-attempts")
+                .value_name("NUM")
+                .help("Number of delivery attempts before notifications are dropped")
+                .takes_value(true)
+                .default_value(DEFAULT_WEBHOOK_ATTEMPTS)
+                .validator(int_gte_1),
+            Arg::with_name("sse")
+                .long("sse")
+                .value_name("ALGORITHM")
+                .help("Server-side encryption, with keys managed by S3 (AES256) or KMS (aws:kms)")
+                .takes_value(true)
+                .possible_values(&["AES256", "aws:kms"]),
+            Arg::with_name("sse_kms_key_id")
+                .long("sse-kms-key-id")
+                .value_name("KEY_ID")
+                .help("KMS key used with --sse aws:kms, instead of the AWS managed key")
+                .takes_value(true)
+                .requires("sse"),
+            Arg::with_name("sse_c_key_file")
+                .long("sse-c-key-file")
+                .value_name("FILE")
+                .help("Encrypt with a customer provided key (SSE-C), read from FILE as 32 raw or base64 encoded bytes")
+                .takes_value(true)
+                .conflicts_with("sse")
+                .validator(is_sse_c_key_file),
+            Arg::with_name("encrypt_key_file")
+                .long("encrypt-key-file")
+                .value_name("FILE")
+                .help("Encrypt files before uploading them, with data keys wrapped by the 32 raw or base64 encoded bytes of FILE")
+                .takes_value(true)
+                .validator(is_cse_key_file),
+            Arg::with_name("encrypt_recipient")
+                .long("encrypt-recipient")
+                .value_name("RECIPIENT")
+                .help("Encrypt files before uploading them, with data keys encrypted for this age X25519 recipient. Can be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with("encrypt_key_file")
+                .validator(is_age_recipient),
+        ];
+
         let matches = App::new("S3 File Sync")
             .version("0.0.1")
             .author("Vlad Vasiliu")
             .about("Sync directories to S3")
-            .settings(&[AppSettings::ColoredHelp, AppSettings::ColorAuto])
+            .settings(&[
+                AppSettings::ColoredHelp,
+                AppSettings::ColorAuto,
+                AppSettings::SubcommandsNegateReqs,
+                AppSettings::VersionlessSubcommands,
+            ])
             .arg(
-                Arg::with_name("watch_dir")
-                    .short("w")
-                    .long("watch-dir")
-                    .value_name("DIR")
-                    .help("Directories to watch")
+                Arg::with_name("database")
+                    .short("d")
+                    .long("database")
+                    .value_name("FILE")
+                    .help("Path to the SQLite database tracking handled files")
                     .takes_value(true)
-                    .required(true)
-                    .min_values(1)
-                    .multiple(true),
+                    .global(true),
+            )
+            .args(&log_args())
+            .arg(
+                Arg::with_name("dry_run")
+                    .short("n")
+                    .long("dry-run")
+                    .help("Log what would be uploaded or changed without doing it")
+                    .global(true),
             )
+            .args(&sync_args)
             .arg(
                 Arg::with_name("watch_interval")
                     .short("i")
                     .long("watcher-interval")
                     .value_name("DURATION")
-                    .help(&format!(
-                        "File change notifications interval. Must be at least {}",
-                        MIN_WATCHER_INTERVAL
-                    ))
+                    .help(&watch_interval_help)
                     .takes_value(true)
                     .required(false)
                     .default_value(&watcher_interval_default)
                     .validator(int_gte_1),
             )
-            .arg(
-                Arg::with_name("bucket_name")
-                    .short("b")
-                    .long("bucket")
-                    .value_name("BUCKET")
-                    .help("AWS bucket name")
-                    .takes_value(true)
-                    .required(true),
+            .subcommand(
+                SubCommand::with_name("sync")
+                    .about("Upload the files not uploaded yet and exit")
+                    .args(&sync_args),
             )
-            .arg(
-                Arg::with_name("upload_size")
-                    .short("s")
-                    .long("upload-part-size")
-                    .value_name("SIZE")
-                    .help(&format!(
-                        "Upload part size in MB. Must be between {} and {}",
-                        MIN_UPLOAD_SIZE, MAX_UPLOAD_SIZE
-                    ))
-                    .takes_value(true)
-                    .required(false)
-                    .default_value(&upload_size_default)
-                    .validator(upload_size_between_bounds),
+            .subcommand(
+                SubCommand::with_name("status")
+                    .about("Print a summary of the files tracked in the database")
+                    .arg(output_format_arg()),
             )
-            .arg(
-                Arg::with_name("uploader_threads")
-                    .short("u")
-                    .long("uploader-threads")
-                    .value_name("NUM")
-                    .help("Number of uploader threads")
-                    .takes_value(true)
-                    .required(false)
-                    .default_value(&uploader_threads_default)
-                    .validator(int_gte_1),
+            .subcommand(
+                SubCommand::with_name("list")
+                    .about("List the files tracked in the database")
+                    .args(&file_filter_args())
+                    .group(file_status_group())
+                    .arg(output_format_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("show")
+                    .about("Show a file's record and history")
+                    .arg(
+                        Arg::with_name("path")
+                            .value_name("PATH")
+                            .help("Path of the file")
+                            .required(true),
+                    )
+                    .arg(output_format_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("requeue")
+                    .about("Mark files to be uploaded again on the next run")
+                    .args(&file_filter_args())
+                    .group(file_status_group())
+                    .group(file_filter_group()),
             )
-            .get_matches();
+            .subcommand(
+                SubCommand::with_name("forget")
+                    .about("Remove files from the database, so that they are handled as new files")
+                    .args(&file_filter_args())
+                    .group(file_status_group())
+                    .group(file_filter_group()),
+            )
+            .get_matches_from(args);
+
+        let command = match matches.subcommand() {
+            ("status", Some(sub_matches)) => Command::Status {
+                format: output_format(sub_matches),
+            },
+            ("list", Some(sub_matches)) => Command::List {
+                filter: file_filter(sub_matches),
+                format: output_format(sub_matches),
+            },
+            ("show", Some(sub_matches)) => Command::Show {
+                path: sub_matches.value_of("path").unwrap().into(),
+                format: output_format(sub_matches),
+            },
+            ("requeue", Some(sub_matches)) => Command::Requeue {
+                filter: file_filter(sub_matches),
+                dry_run: sub_matches.is_present("dry_run"),
+            },
+            ("forget", Some(sub_matches)) => Command::Forget {
+                filter: file_filter(sub_matches),
+                dry_run: sub_matches.is_present("dry_run"),
+            },
+            ("sync", Some(sub_matches)) => Command::Sync(SyncConfig::from_matches(sub_matches)),
+            _ => Command::Watch(SyncConfig::from_matches(&matches)),
+        };
+
+        // Global arguments end up in the matches of the subcommand they were given after
+        let database_path = matches
+            .subcommand()
+            .1
+            .and_then(|sub_matches| sub_matches.value_of("database"))
+            .or_else(|| matches.value_of("database"))
+            .unwrap_or(DEFAULT_DATABASE_PATH)
+            .into();
 
         Self {
-            watched_dirs: matches
-                .values_of("watch_dir")
-                .unwrap()
-                .map(|e| e.into())
-                .collect(),
-            bucket_name: matches.value_of("bucket_name").unwrap().into(),
+            database_path,
+            log: log_config(&matches),
+            command,
+        }
+    }
+}
+
+impl SyncConfig {
+    fn from_matches(matches: &ArgMatches) -> Self {
+        let mut watched_dirs: Vec<String> = matches
+            .values_of("watch_dir")
+            .map_or_else(Vec::new, |dirs| dirs.map(String::from).collect());
+        let mut queues: Vec<(PathBuf, QueueSettings)> = watched_dirs
+            .iter()
+            .map(|dir| (PathBuf::from(dir), QueueSettings::default()))
+            .collect();
+        let mut rules = Vec::new();
+        let mut destinations: Vec<(String, Destination)> = default_destination(matches)
+            .map(|destination| (DEFAULT_DESTINATION.into(), destination))
+            .into_iter()
+            .collect();
+        // Directories given with --watch-dir use the default destination
+        let mut uses_default = !watched_dirs.is_empty();
+        if let Some(path) = matches.value_of("config") {
+            // Loaded again, as the validator can't keep what it parsed
+            let settings = file::load(Path::new(path)).unwrap_or_else(|err| {
+                clap::Error::with_description(
+                    &format!("Invalid configuration file {}: {}", path, err),
+                    ErrorKind::InvalidValue,
+                )
+                .exit()
+            });
+            for watch_dir in settings.watch_dirs {
+                let names = &watch_dir.rules.destinations;
+                uses_default |=
+                    names.is_empty() || names.iter().any(|name| name == DEFAULT_DESTINATION);
+                watched_dirs.push(watch_dir.dir.clone());
+                queues.push((PathBuf::from(&watch_dir.dir), watch_dir.queue));
+                rules.push((PathBuf::from(watch_dir.dir), watch_dir.rules));
+            }
+            destinations.extend(settings.destinations);
+        }
+        if uses_default
+            && destinations.first().map(|(name, _)| name.as_str()) != Some(DEFAULT_DESTINATION)
+        {
+            clap::Error::with_description(
+                "--bucket or --destination-dir is required for the directories without destinations",
+                ErrorKind::MissingRequiredArgument,
+            )
+            .exit()
+        }
+
+        Self {
+            watched_dirs,
+            rules: RuleSet::new(rules),
+            destinations,
+            credentials: credentials(matches),
+            key_template: KeyTemplate::parse(
+                matches.value_of("key_template").unwrap(),
+                key_date(matches),
+            )
+            .unwrap()
+            .with_policy(key_policy(matches)),
             num_uploaders: matches
                 .value_of("uploader_threads")
                 .unwrap()
                 .parse()
                 .unwrap(),
             upload_part_size: matches.value_of("upload_size").unwrap().parse().unwrap(),
-            watcher_delay: matches.value_of("watch_interval").unwrap().parse().unwrap(),
+            throttle: throttle(matches),
+            queues,
+            queue_window: matches.value_of("queue_window").unwrap().parse().unwrap(),
+            smallest_first: matches.is_present("smallest_first"),
+            watcher_delay: matches
+                .value_of("watch_interval")
+                .map_or(DEFAULT_WATCHER_INTERVAL, |delay| delay.parse().unwrap()),
+            listen_address: matches.value_of("listen").map(String::from),
+            dry_run: matches.is_present("dry_run"),
+            audit_log: matches.value_of("audit_log").map(PathBuf::from),
+            sse: server_side_encryption(matches),
+            cse: client_side_encryption(matches),
+            webhook: matches.value_of("webhook_url").map(|url| WebhookConfig {
+                url: url.into(),
+                batch_size: matches
+                    .value_of("webhook_batch_size")
+                    .unwrap()
+                    .parse()
+                    .unwrap(),
+                max_attempts: matches
+                    .value_of("webhook_attempts")
+                    .unwrap()
+                    .parse()
+                    .unwrap(),
+            }),
         }
     }
 
     pub fn pretty_string(&self) -> String {
         let mut result = String::from("Supplied configuration:\n");
+        if self.dry_run {
+            result.push_str("\tDry run:\tnothing will be uploaded\n");
+        }
         result.push_str("\tUploader:\n");
-        result.push_str(&format!("\t\tBucket name:\t{}\n", self.bucket_name));
+        for (name, destination) in &self.destinations {
+            result.push_str(&format!("\t\tDestination:\t{}\n", name));
+            match destination {
+                Destination::S3 {
+                    bucket_name,
+                    region,
+                } => {
+                    result.push_str(&format!("\t\t\tBucket name:\t{}\n", bucket_name));
+                    match region {
+                        Region::Custom { endpoint, .. } => {
+                            result.push_str(&format!("\t\t\tEndpoint:\t{}\n", endpoint))
+                        }
+                        region => result.push_str(&format!("\t\t\tRegion:\t\t{}\n", region.name())),
+                    }
+                }
+                Destination::Directory(path) => {
+                    result.push_str(&format!("\t\t\tDirectory:\t{}\n", path.display()))
+                }
+            }
+        }
+        result.push_str(&format!("\t\tCredentials:\t{}\n", self.credentials));
+        result.push_str(&format!("\t\tKey template:\t{}\n", self.key_template));
         result.push_str(&format!("\t\tThreads:\t{}\n", self.num_uploaders));
         result.push_str(&format!("\t\tPart size:\t{} MB\n", self.upload_part_size));
+        if let Some(throttle) = &self.throttle {
+            result.push_str(&format!("\t\tBandwidth:\t{}\n", throttle.schedule()));
+        }
+        result.push_str(&format!("\t\tQueue window:\t{} files\n", self.queue_window));
+        if self.smallest_first {
+            result.push_str("\t\tOrder:\t\tsmallest first\n");
+        }
+        if let Some(address) = &self.listen_address {
+            result.push_str(&format!("\tHTTP server:\thttp://{}\n", address));
+        }
+        if let Some(path) = &self.audit_log {
+            result.push_str(&format!("\tAudit log:\t{}\n", path.display()));
+        }
+        match &self.sse {
+            ServerSideEncryption::BucketDefault => (),
+            ServerSideEncryption::S3 => result.push_str("\tEncryption:\tSSE-S3\n"),
+            ServerSideEncryption::Kms { key_id: None } => {
+                result.push_str("\tEncryption:\tSSE-KMS\n")
+            }
+            ServerSideEncryption::Kms { key_id: Some(id) } => {
+                result.push_str(&format!("\tEncryption:\tSSE-KMS ({})\n", id))
+            }
+            ServerSideEncryption::Customer { .. } => result.push_str("\tEncryption:\tSSE-C\n"),
+        }
+        match &self.cse {
+            None => (),
+            Some(ClientSideEncryption::KeyFile { .. }) => {
+                result.push_str("\tClient-side encryption:\tkey file\n")
+            }
+            Some(ClientSideEncryption::Recipients(recipients)) => result.push_str(&format!(
+                "\tClient-side encryption:\tage, {} recipient(s)\n",
+                recipients.len()
+            )),
+        }
+        if let Some(webhook) = &self.webhook {
+            result.push_str(&format!("\tWebhook:\t{}\n", webhook.url));
+        }
         result.push_str("\tWatcher:\n");
         result.push_str(&format!("\t\tDelay:\t\t{}s\n", self.watcher_delay));
         result.push_str("\t\tDirectories:\n");
@@ -121,6 +684,435 @@ impl Config {
     }
 }
 
+#[cfg(test)]
+impl SyncConfig {
+    /// Parses the arguments of a `sync` command, as given after `sync`
+    pub fn for_test(args: &[&str]) -> Self {
+        let mut all_args = vec!["s3_file_sync", "sync"];
+        all_args.extend(args);
+        match Config::from_iter(all_args).command {
+            Command::Sync(config) => config,
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn default_destination(matches: &ArgMatches) -> Option<Destination> {
+    if let Some(path) = matches.value_of("destination_dir") {
+        return Some(Destination::Directory(path.into()));
+    }
+    matches
+        .value_of("bucket_name")
+        .map(|bucket_name| Destination::S3 {
+            bucket_name: bucket_name.into(),
+            region: region(matches),
+        })
+}
+
+fn region(matches: &ArgMatches) -> Region {
+    let name = matches.value_of("region").unwrap();
+    match matches.value_of("endpoint_url") {
+        Some(endpoint) => Region::Custom {
+            name: name.into(),
+            endpoint: endpoint.into(),
+        },
+        // Any name goes with a custom endpoint, as S3-compatible stores have their own
+        None => Region::from_str(name).unwrap_or_else(|err| {
+            clap::Error::with_description(&err.to_string(), ErrorKind::InvalidValue).exit()
+        }),
+    }
+}
+
+fn key_date(matches: &ArgMatches) -> KeyDate {
+    match matches.value_of("key_date") {
+        Some("detected") => KeyDate::Detected,
+        _ => KeyDate::Modified,
+    }
+}
+
+fn throttle(matches: &ArgMatches) -> Option<Arc<Throttle>> {
+    let schedule = Schedule {
+        default: matches
+            .value_of("bandwidth_limit")
+            .and_then(|rate| throttle::parse_rate(rate).unwrap()),
+        windows: matches
+            .values_of("bandwidth_schedule")
+            .map_or_else(Vec::new, |windows| {
+                windows
+                    .map(|window| Window::parse(window).unwrap())
+                    .collect()
+            }),
+    };
+    if schedule.is_unlimited() {
+        None
+    } else {
+        Some(Arc::new(Throttle::new(schedule)))
+    }
+}
+
+fn credentials(matches: &ArgMatches) -> Credentials {
+    let assume_role = matches.value_of("assume_role").map(|role_arn| {
+        AssumeRole::new(
+            role_arn,
+            matches.value_of("assume_role_external_id"),
+            matches.value_of("assume_role_session_name"),
+        )
+        .unwrap()
+    });
+    Credentials::new(
+        matches.value_of("aws_profile"),
+        matches.value_of("aws_keys_file").map(Path::new),
+        assume_role,
+    )
+    .unwrap()
+}
+
+fn key_policy(matches: &ArgMatches) -> KeyPolicy {
+    KeyPolicy {
+        nfc: matches.is_present("key_nfc"),
+        non_utf8: match matches.value_of("key_non_utf8") {
+            Some("lossy") => NonUtf8Policy::Lossy,
+            Some("reject") => NonUtf8Policy::Reject,
+            _ => NonUtf8Policy::Escape,
+        },
+        unsafe_chars: match matches.value_of("key_unsafe_chars") {
+            Some("escape") => UnsafeCharPolicy::Escape,
+            Some("reject") => UnsafeCharPolicy::Reject,
+            _ => UnsafeCharPolicy::Keep,
+        },
+        max_length: matches.value_of("key_max_length").unwrap().parse().unwrap(),
+    }
+}
+
+fn server_side_encryption(matches: &ArgMatches) -> ServerSideEncryption {
+    if let Some(path) = matches.value_of("sse_c_key_file") {
+        return ServerSideEncryption::from_key_file(Path::new(path)).unwrap();
+    }
+
+    let key_id = matches.value_of("sse_kms_key_id").map(String::from);
+    match matches.value_of("sse") {
+        Some("aws:kms") => ServerSideEncryption::Kms { key_id },
+        Some(_) if key_id.is_some() => clap::Error::with_description(
+            "--sse-kms-key-id can only be used with --sse aws:kms",
+            ErrorKind::ArgumentConflict,
+        )
+        .exit(),
+        Some(_) => ServerSideEncryption::S3,
+        None => ServerSideEncryption::BucketDefault,
+    }
+}
+
+fn client_side_encryption(matches: &ArgMatches) -> Option<ClientSideEncryption> {
+    if let Some(path) = matches.value_of("encrypt_key_file") {
+        return Some(ClientSideEncryption::from_key_file(Path::new(path)).unwrap());
+    }
+    matches
+        .values_of("encrypt_recipient")
+        .map(|recipients| ClientSideEncryption::from_recipients(recipients).unwrap())
+}
+
+fn is_cse_key_file(path: String) -> Result<(), String> {
+    ClientSideEncryption::from_key_file(Path::new(&path)).map(|_| ())
+}
+
+fn is_age_recipient(recipient: String) -> Result<(), String> {
+    ClientSideEncryption::from_recipients(std::iter::once(recipient.as_str())).map(|_| ())
+}
+
+fn is_config_file(path: String) -> Result<(), String> {
+    file::load(Path::new(&path)).map(|_| ())
+}
+
+fn is_sse_c_key_file(path: String) -> Result<(), String> {
+    ServerSideEncryption::from_key_file(Path::new(&path)).map(|_| ())
+}
+
+fn log_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("log_level")
+            .long("log-level")
+            .value_name("LEVEL")
+            .help("Log level")
+            .takes_value(true)
+            .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+            .default_value(DEFAULT_LOG_LEVEL)
+            .global(true),
+        Arg::with_name("log_module")
+            .long("log-module")
+            .value_name("MODULE=LEVEL")
+            .help("Log level for a module, ex: uploader=debug. Can be repeated")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .validator(is_module_level)
+            .global(true),
+        Arg::with_name("log_file")
+            .long("log-file")
+            .value_name("FILE")
+            .help("Also write logs to FILE")
+            .takes_value(true)
+            .global(true),
+        Arg::with_name("log_rotate")
+            .long("log-rotate")
+            .value_name("WHEN")
+            .help("Rotate the log file daily or when it reaches a size, ex: daily, 10MB, 500KB")
+            .takes_value(true)
+            .requires("log_file")
+            .validator(is_rotation)
+            .global(true),
+        Arg::with_name("log_keep")
+            .long("log-keep")
+            .value_name("NUM")
+            .help("Number of rotated log files to keep")
+            .takes_value(true)
+            .default_value(DEFAULT_LOG_KEEP)
+            .validator(|num| {
+                num.parse::<usize>()
+                    .map(|_| ())
+                    .map_err(|err| err.to_string())
+            })
+            .global(true),
+        Arg::with_name("log_format")
+            .long("log-format")
+            .value_name("FORMAT")
+            .help("Log format, json writes one object per line")
+            .takes_value(true)
+            .possible_values(&["text", "json"])
+            .default_value("text")
+            .global(true),
+    ]
+}
+
+/// Reads the logging options, wherever they were given
+fn log_config(matches: &ArgMatches) -> LogConfig {
+    let sub_matches = matches.subcommand().1;
+    let value_of = |name| {
+        sub_matches
+            .filter(|m| m.occurrences_of(name) > 0)
+            .unwrap_or(matches)
+            .value_of(name)
+    };
+
+    let mut modules: Vec<(String, LevelFilter)> = Vec::new();
+    for m in std::iter::once(matches).chain(sub_matches) {
+        if let Some(values) = m.values_of("log_module") {
+            modules.extend(values.map(|value| parse_module_level(value).unwrap()));
+        }
+    }
+
+    LogConfig {
+        level: value_of("log_level").unwrap().parse().unwrap(),
+        modules,
+        file: value_of("log_file").map(PathBuf::from),
+        rotation: value_of("log_rotate").map_or(Rotation::Never, |r| parse_rotation(r).unwrap()),
+        keep: value_of("log_keep").unwrap().parse().unwrap(),
+        format: match value_of("log_format") {
+            Some("json") => LogFormat::Json,
+            _ => LogFormat::Text,
+        },
+    }
+}
+
+/// Parses `module=level`, modules of this program can be given without the crate prefix
+fn parse_module_level(value: &str) -> Result<(String, LevelFilter), String> {
+    let mut parts = value.splitn(2, '=');
+    let module = parts.next().unwrap_or_default();
+    let level = parts
+        .next()
+        .ok_or_else(|| String::from("Must be formatted as MODULE=LEVEL"))?
+        .parse::<LevelFilter>()
+        .map_err(|_| String::from("Level must be one of off, error, warn, info, debug, trace"))?;
+
+    if module.is_empty() {
+        return Err("Module name can't be empty".into());
+    }
+    let top_level = module.split("::").next().unwrap_or_default();
+    let module = if OWN_MODULES.contains(&top_level) {
+        format!("{}::{}", env!("CARGO_PKG_NAME"), module)
+    } else {
+        module.to_owned()
+    };
+    Ok((module, level))
+}
+
+fn is_module_level(value: String) -> Result<(), String> {
+    parse_module_level(&value).map(|_| ())
+}
+
+fn parse_rotation(value: &str) -> Result<Rotation, String> {
+    let value = value.to_lowercase();
+    let (number, multiplier) = if value == "daily" {
+        return Ok(Rotation::Daily);
+    } else if let Some(number) = value.strip_suffix("kb") {
+        (number, 1024)
+    } else if let Some(number) = value.strip_suffix("mb") {
+        (number, 1024 * 1024)
+    } else if let Some(number) = value.strip_suffix("gb") {
+        (number, 1024 * 1024 * 1024)
+    } else {
+        return Err("Must be daily or a size such as 500KB, 10MB or 1GB".into());
+    };
+
+    match number.parse::<u64>() {
+        Ok(x) if x > 0 => Ok(Rotation::Size(x * multiplier)),
+        _ => Err("Size must be a positive integer".into()),
+    }
+}
+
+fn is_rotation(value: String) -> Result<(), String> {
+    parse_rotation(&value).map(|_| ())
+}
+
+fn output_format_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("output")
+        .short("o")
+        .long("output")
+        .value_name("FORMAT")
+        .help("Output format")
+        .takes_value(true)
+        .possible_values(&["table", "json"])
+        .default_value("table")
+}
+
+fn output_format(matches: &ArgMatches) -> OutputFormat {
+    match matches.value_of("output") {
+        Some("json") => OutputFormat::Json,
+        _ => OutputFormat::Table,
+    }
+}
+
+fn file_filter_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("pending")
+            .long("pending")
+            .help("Only files waiting to be uploaded"),
+        Arg::with_name("failed")
+            .long("failed")
+            .help("Only files whose upload failed"),
+        Arg::with_name("uploaded")
+            .long("uploaded")
+            .help("Only uploaded files"),
+        Arg::with_name("glob")
+            .long("glob")
+            .value_name("PATTERN")
+            .help("Only files whose full path matches the pattern, ex: '/data/2020-01-*'")
+            .takes_value(true),
+        Arg::with_name("since")
+            .long("since")
+            .value_name("DATE")
+            .help("Only files first seen at or after DATE (UTC, YYYY-MM-DD[ HH:MM:SS])")
+            .takes_value(true)
+            .validator(is_date),
+        Arg::with_name("before")
+            .long("before")
+            .value_name("DATE")
+            .help("Only files first seen before DATE (UTC, YYYY-MM-DD[ HH:MM:SS])")
+            .takes_value(true)
+            .validator(is_date),
+    ]
+}
+
+fn file_status_group<'a>() -> ArgGroup<'a> {
+    ArgGroup::with_name("status").args(&["pending", "failed", "uploaded"])
+}
+
+/// Requires at least one filter, so that a command doesn't apply to every file by accident
+fn file_filter_group<'a>() -> ArgGroup<'a> {
+    ArgGroup::with_name("filter")
+        .args(&["pending", "failed", "uploaded", "glob", "since", "before"])
+        .multiple(true)
+        .required(true)
+}
+
+fn file_filter(matches: &ArgMatches) -> FileFilter {
+    FileFilter {
+        status: if matches.is_present("pending") {
+            FileStatus::Pending
+        } else if matches.is_present("failed") {
+            FileStatus::Failed
+        } else if matches.is_present("uploaded") {
+            FileStatus::Uploaded
+        } else {
+            FileStatus::All
+        },
+        glob: matches.value_of("glob").map(String::from),
+        since: matches.value_of("since").map(String::from),
+        before: matches.value_of("before").map(String::from),
+    }
+}
+
+fn is_date(date: String) -> Result<(), String> {
+    NaiveDateTime::parse_from_str(&date, "%Y-%m-%d %H:%M:%S")
+        .map(|_| ())
+        .or_else(|_| NaiveDate::parse_from_str(&date, "%Y-%m-%d").map(|_| ()))
+        .map_err(|_| "Must be a date formatted as YYYY-MM-DD or YYYY-MM-DD HH:MM:SS".into())
+}
+
+fn is_socket_address(address: String) -> Result<(), String> {
+    address
+        .parse::<SocketAddr>()
+        .map(|_| ())
+        .map_err(|_| "Must be an IP address and port, ex: 127.0.0.1:9100".into())
+}
+
+fn is_dir(path: String) -> Result<(), String> {
+    if Path::new(&path).is_dir() {
+        Ok(())
+    } else {
+        Err(format!("{} is not a directory", path))
+    }
+}
+
+fn is_http_url(url: String) -> Result<(), String> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(())
+    } else {
+        Err("Must be an http:// or https:// URL".into())
+    }
+}
+
+fn is_endpoint_url(url: String) -> Result<(), String> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid endpoint {}, expected an http:// or https:// URL",
+            url
+        ))
+    }
+}
+
+fn is_rate(rate: String) -> Result<(), String> {
+    throttle::parse_rate(&rate).map(|_| ())
+}
+
+fn is_bandwidth_window(window: String) -> Result<(), String> {
+    Window::parse(&window).map(|_| ())
+}
+
+fn is_keys_file(path: String) -> Result<(), String> {
+    StaticKeys::from_file(Path::new(&path)).map(|_| ())
+}
+
+fn is_role_arn(role_arn: String) -> Result<(), String> {
+    credentials::check_role_arn(&role_arn)
+}
+
+fn is_session_name(name: String) -> Result<(), String> {
+    credentials::check_session_name(&name)
+}
+
+fn is_key_template(template: String) -> Result<(), String> {
+    KeyTemplate::parse(&template, KeyDate::Modified).map(|_| ())
+}
+
+fn key_length_within_bounds(length: String) -> Result<(), String> {
+    match length.parse::<usize>().map_err(|err| format!("{}", err))? {
+        x if (1..=MAX_KEY_LENGTH).contains(&x) => Ok(()),
+        _ => Err(format!("Must be between 1 and {}", MAX_KEY_LENGTH)),
+    }
+}
+
 fn int_gte_1(num: String) -> Result<(), String> {
     match num.parse::<u64>().or_else(|err| Err(format!("{}", err)))? {
         x if x < 1 => Err("Must be greater than or equal to 1.".into()),
@@ -140,7 +1132,12 @@ fn upload_size_between_bounds(num: String) -> Result<(), String> {
 
 #[cfg(test)]
 mod tests {
-    use super::{int_gte_1, upload_size_between_bounds, MAX_UPLOAD_SIZE, MIN_UPLOAD_SIZE};
+    use super::{
+        int_gte_1, is_date, parse_module_level, parse_rotation, upload_size_between_bounds,
+        MAX_UPLOAD_SIZE, MIN_UPLOAD_SIZE,
+    };
+    use crate::logging::Rotation;
+    use log::LevelFilter;
 
     use proptest::prelude::*;
 
@@ -186,6 +1183,58 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_is_date_works_for_dates_and_datetimes() {
+        assert!(is_date("2020-02-29".into()).is_ok());
+        assert!(is_date("2020-02-29 13:45:00".into()).is_ok());
+    }
+
+    #[test]
+    fn test_is_date_breaks_for_invalid_dates() {
+        for date in &[
+            "2019-02-29",
+            "2020-02-29T13:45:00",
+            "29/02/2020",
+            "test",
+            "",
+        ] {
+            assert!(is_date(date.to_string()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_module_level_prefixes_own_modules() {
+        assert_eq!(
+            parse_module_level("uploader=warn"),
+            Ok(("s3_file_sync::uploader".into(), LevelFilter::Warn))
+        );
+        assert_eq!(
+            parse_module_level("rusoto_core=trace"),
+            Ok(("rusoto_core".into(), LevelFilter::Trace))
+        );
+        assert_eq!(
+            parse_module_level("uploader::breaker=debug"),
+            Ok(("s3_file_sync::uploader::breaker".into(), LevelFilter::Debug))
+        );
+        assert_eq!(
+            parse_module_level("hyper=debug"),
+            Ok(("hyper".into(), LevelFilter::Debug))
+        );
+        assert!(parse_module_level("uploader").is_err());
+        assert!(parse_module_level("=debug").is_err());
+        assert!(parse_module_level("uploader=loud").is_err());
+    }
+
+    #[test]
+    fn test_parse_rotation() {
+        assert_eq!(parse_rotation("daily"), Ok(Rotation::Daily));
+        assert_eq!(parse_rotation("10MB"), Ok(Rotation::Size(10 * 1024 * 1024)));
+        assert_eq!(parse_rotation("500kb"), Ok(Rotation::Size(500 * 1024)));
+        for value in &["0MB", "MB", "10", "weekly", "-1KB"] {
+            assert!(parse_rotation(value).is_err());
+        }
+    }
+
     #[test]
     fn int_gte_1_doesnt_crash() {
         proptest!(|(s in "\\PC*")| {
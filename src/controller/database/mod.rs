@@ -1,13 +1,240 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+use chrono::NaiveDateTime;
 use libsqlite3_sys::{Error as LibSQLError, ErrorCode as LibSQLErrorCode};
-use rusqlite::{Connection, Error as SQLError, OpenFlags};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Value, ValueRef};
+use rusqlite::{params, Connection, Error as SQLError, OpenFlags, Row, ToSql, NO_PARAMS};
+use serde::Serialize;
 
 use crate::controller::file::File;
 
 pub mod error;
 use error::{Error, Result};
 
+/// Schema changes, applied in order on open
+///
+/// The number of applied migrations is tracked with SQLite's `user_version` pragma.
+/// Existing migrations must never be modified, only new ones appended.
+static MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS File (
+             path            TEXT PRIMARY KEY,
+             first_seen_date TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+             uploaded_date   TEXT,
+             deleted_date    TEXT
+     );
+     CREATE INDEX IF NOT EXISTS file_uploaded ON File ( uploaded_date );
+     CREATE INDEX IF NOT EXISTS file_not_deleted ON File ( deleted_date )
+             WHERE deleted_date IS NULL and uploaded_date IS NOT NULL;",
+    "ALTER TABLE File ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+     ALTER TABLE File ADD COLUMN failed_date TEXT;
+     ALTER TABLE File ADD COLUMN last_error TEXT;
+     CREATE TABLE IF NOT EXISTS FileEvent (
+             id              INTEGER PRIMARY KEY,
+             path            TEXT NOT NULL,
+             date            TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+             kind            TEXT NOT NULL,
+             detail          TEXT
+     );
+     CREATE INDEX IF NOT EXISTS file_event_path ON FileEvent ( path );",
+    "ALTER TABLE File ADD COLUMN key TEXT;",
+    "CREATE TABLE IF NOT EXISTS FileDestination (
+             path            TEXT NOT NULL,
+             destination     TEXT NOT NULL,
+             uploaded_date   TEXT,
+             failed_date     TEXT,
+             attempts        INTEGER NOT NULL DEFAULT 0,
+             last_error      TEXT,
+             PRIMARY KEY ( path, destination )
+     );",
+];
+
+/// Upload state of a file, as recorded in the database
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FileStatus {
+    #[default]
+    All,
+    Pending,
+    Failed,
+    Uploaded,
+}
+
+impl FileStatus {
+    fn condition(self) -> &'static str {
+        match self {
+            Self::All => "1",
+            Self::Pending => "uploaded_date IS NULL AND failed_date IS NULL",
+            Self::Failed => "uploaded_date IS NULL AND failed_date IS NOT NULL",
+            Self::Uploaded => "uploaded_date IS NOT NULL",
+        }
+    }
+}
+
+/// Selects files by state, path and first seen date
+#[derive(Debug, Default)]
+pub struct FileFilter {
+    pub status: FileStatus,
+    /// SQLite `GLOB` pattern, matched against the full path
+    pub glob: Option<String>,
+    /// Only files first seen at or after this date
+    pub since: Option<String>,
+    /// Only files first seen before this date
+    pub before: Option<String>,
+}
+
+impl FileFilter {
+    #[cfg(test)]
+    pub fn with_status(status: FileStatus) -> Self {
+        Self {
+            status,
+            ..Default::default()
+        }
+    }
+
+    /// Builds the `WHERE` clause and its parameters
+    fn condition(&self) -> (String, Vec<&dyn ToSql>) {
+        let mut conditions = vec![self.status.condition().to_owned()];
+        let mut parameters: Vec<&dyn ToSql> = Vec::new();
+
+        if let Some(glob) = &self.glob {
+            conditions.push("path GLOB ?".into());
+            parameters.push(glob);
+        }
+        if let Some(since) = &self.since {
+            conditions.push("first_seen_date >= DATETIME(?)".into());
+            parameters.push(since);
+        }
+        if let Some(before) = &self.before {
+            conditions.push("first_seen_date < DATETIME(?)".into());
+            parameters.push(before);
+        }
+
+        (conditions.join(" AND "), parameters)
+    }
+}
+
+/// A path as stored in the database
+///
+/// Paths are stored as text when they are valid UTF-8, so that they can be read and matched with `--glob`,
+/// and as a blob of their raw bytes otherwise, so that the file can still be found on disk.
+struct StoredPath<'a>(&'a Path);
+
+impl ToSql for StoredPath<'_> {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self.0.to_str() {
+            Some(path) => ToSqlOutput::from(path),
+            None => ToSqlOutput::Owned(Value::Blob(path_to_bytes(self.0))),
+        })
+    }
+}
+
+/// Reads a path stored as `StoredPath`
+struct LoadedPath(PathBuf);
+
+impl FromSql for LoadedPath {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Text(text) => std::str::from_utf8(text)
+                .map(|path| Self(path.into()))
+                .map_err(|err| FromSqlError::Other(Box::new(err))),
+            ValueRef::Blob(bytes) => Ok(Self(path_from_bytes(bytes))),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::OsStr::from_bytes(bytes).into()
+}
+
+#[cfg(windows)]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::windows::ffi::OsStrExt;
+    path.as_os_str()
+        .encode_wide()
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+#[cfg(windows)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::windows::ffi::OsStringExt;
+    let wide: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .collect();
+    std::ffi::OsString::from_wide(&wide).into()
+}
+
+/// A row of the `File` table
+#[derive(Debug, Serialize)]
+pub struct FileRecord {
+    /// For display, see `full_path` for the actual path
+    pub path: String,
+    #[serde(skip)]
+    pub full_path: PathBuf,
+    pub key: Option<String>,
+    pub first_seen_date: NaiveDateTime,
+    pub uploaded_date: Option<NaiveDateTime>,
+    pub deleted_date: Option<NaiveDateTime>,
+    pub failed_date: Option<NaiveDateTime>,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+}
+
+impl FileRecord {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let LoadedPath(full_path) = row.get("path")?;
+        Ok(Self {
+            path: full_path.to_string_lossy().into_owned(),
+            full_path,
+            key: row.get("key")?,
+            first_seen_date: row.get("first_seen_date")?,
+            uploaded_date: row.get("uploaded_date")?,
+            deleted_date: row.get("deleted_date")?,
+            failed_date: row.get("failed_date")?,
+            attempts: row.get("attempts")?,
+            last_error: row.get("last_error")?,
+        })
+    }
+}
+
+/// Upload state of a file at one of its destinations
+#[derive(Debug, Serialize)]
+pub struct DestinationRecord {
+    pub destination: String,
+    pub uploaded_date: Option<NaiveDateTime>,
+    pub failed_date: Option<NaiveDateTime>,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+}
+
+/// Something that happened to a file, such as its detection or an upload attempt
+#[derive(Debug, Serialize)]
+pub struct FileEvent {
+    pub date: NaiveDateTime,
+    pub kind: String,
+    pub detail: Option<String>,
+}
+
+/// File counts per state
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub total: i64,
+    pub uploaded: i64,
+    pub pending: i64,
+    pub failed: i64,
+    pub deleted: i64,
+    pub oldest_pending: Option<FileRecord>,
+}
+
 pub struct Database {
     connection: Connection,
 }
@@ -26,32 +253,31 @@ impl Database {
     }
 
     fn init_db(&self) -> Result<()> {
-        self.connection.execute_batch(
-            "BEGIN;
-                  CREATE TABLE IF NOT EXISTS File (
-                          path            TEXT PRIMARY KEY,
-                          first_seen_date TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
-                          uploaded_date   TEXT,
-                          deleted_date    TEXT
-                  );
-                  CREATE INDEX IF NOT EXISTS file_uploaded ON File ( uploaded_date );
-                  CREATE INDEX IF NOT EXISTS file_not_deleted ON File ( deleted_date )
-                          WHERE deleted_date IS NULL and uploaded_date IS NOT NULL;
-              COMMIT;",
-        )?;
+        let version: i64 = self
+            .connection
+            .query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+
+        for (num, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            self.connection.execute_batch(&format!(
+                "BEGIN;
+                    {}
+                    PRAGMA user_version = {};
+                 COMMIT;",
+                migration,
+                num + 1
+            ))?;
+        }
         Ok(())
     }
 
     pub fn add_file(&self, file: &File) -> Result<()> {
         let mut statement = self
             .connection
-            .prepare_cached("INSERT INTO File (path) VALUES (?1)")?;
-        match statement.insert(&[file.full_path.to_str().unwrap()]) {
-            Ok(_) => Ok(()),
+            .prepare_cached("INSERT INTO File (path, key) VALUES (?1, ?2)")?;
+        match statement.insert(params![StoredPath(&file.full_path), file.key]) {
+            Ok(_) => self.add_event(&file.full_path, "detected", None),
             Err(
-                err
-                @
-                SQLError::SqliteFailure(
+                err @ SQLError::SqliteFailure(
                     LibSQLError {
                         code: LibSQLErrorCode::ConstraintViolation,
                         ..
@@ -63,11 +289,256 @@ impl Database {
         }
     }
 
+    pub fn is_known(&self, file: &File) -> Result<bool> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT COUNT(*) FROM File WHERE path = (?1)")?;
+        let count: i64 = statement.query_row(&[StoredPath(&file.full_path)], |row| row.get(0))?;
+        Ok(count > 0)
+    }
+
     pub fn set_upload_date(&self, file: &File) -> Result<()> {
+        let mut statement = self.connection.prepare_cached(
+            "UPDATE File
+             SET uploaded_date = DATETIME('now'), failed_date = NULL, attempts = attempts + 1
+             WHERE path = (?1)",
+        )?;
+        statement.execute(&[StoredPath(&file.full_path)])?;
+        self.add_event(&file.full_path, "uploaded", None)
+    }
+
+    pub fn set_upload_failed(&self, file: &File, reason: &str) -> Result<()> {
+        let mut statement = self.connection.prepare_cached(
+            "UPDATE File
+             SET failed_date = DATETIME('now'), last_error = (?2), attempts = attempts + 1
+             WHERE path = (?1)",
+        )?;
+        statement.execute(params![StoredPath(&file.full_path), reason])?;
+        self.add_event(&file.full_path, "failed", Some(reason))
+    }
+
+    /// Records the upload of a file to one of its destinations
+    ///
+    /// The file itself only counts as uploaded once all its destinations are, see `set_upload_date`.
+    pub fn set_destination_uploaded(&self, file: &File, destination: &str) -> Result<()> {
+        let mut statement = self.connection.prepare_cached(
+            "INSERT INTO FileDestination (path, destination, uploaded_date, attempts)
+             VALUES (?1, ?2, DATETIME('now'), 1)
+             ON CONFLICT (path, destination) DO UPDATE
+             SET uploaded_date = excluded.uploaded_date, failed_date = NULL, attempts = attempts + 1",
+        )?;
+        statement.execute(params![StoredPath(&file.full_path), destination])?;
+        Ok(())
+    }
+
+    pub fn set_destination_failed(
+        &self,
+        file: &File,
+        destination: &str,
+        reason: &str,
+    ) -> Result<()> {
+        let mut statement = self.connection.prepare_cached(
+            "INSERT INTO FileDestination (path, destination, failed_date, attempts, last_error)
+             VALUES (?1, ?2, DATETIME('now'), 1, ?3)
+             ON CONFLICT (path, destination) DO UPDATE
+             SET failed_date = excluded.failed_date, last_error = excluded.last_error,
+                 attempts = attempts + 1",
+        )?;
+        statement.execute(params![StoredPath(&file.full_path), destination, reason])?;
+        Ok(())
+    }
+
+    pub fn summary(&self) -> Result<Summary> {
+        let mut summary = self.connection.query_row(
+            "SELECT COUNT(*),
+                    COUNT(uploaded_date),
+                    COUNT(CASE WHEN uploaded_date IS NULL AND failed_date IS NULL THEN 1 END),
+                    COUNT(CASE WHEN uploaded_date IS NULL AND failed_date IS NOT NULL THEN 1 END),
+                    COUNT(deleted_date)
+             FROM File",
+            NO_PARAMS,
+            |row| {
+                Ok(Summary {
+                    total: row.get(0)?,
+                    uploaded: row.get(1)?,
+                    pending: row.get(2)?,
+                    failed: row.get(3)?,
+                    deleted: row.get(4)?,
+                    oldest_pending: None,
+                })
+            },
+        )?;
+
+        let mut statement = self.connection.prepare_cached(&format!(
+            "SELECT * FROM File WHERE {} ORDER BY first_seen_date LIMIT 1",
+            FileStatus::Pending.condition()
+        ))?;
+        let mut rows = statement.query_map(NO_PARAMS, FileRecord::from_row)?;
+        summary.oldest_pending = rows.next().transpose()?;
+
+        Ok(summary)
+    }
+
+    pub fn list_files(&self, filter: &FileFilter) -> Result<Vec<FileRecord>> {
+        let (condition, parameters) = filter.condition();
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT * FROM File WHERE {} ORDER BY first_seen_date, path",
+            condition
+        ))?;
+        let rows = statement.query_map(parameters, FileRecord::from_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Pending files recorded after the given row, in the order they were recorded, with their row
+    pub fn pending_after(&self, row: i64, limit: usize) -> Result<Vec<(i64, FileRecord)>> {
+        let mut statement = self.connection.prepare_cached(&format!(
+            "SELECT rowid, * FROM File WHERE {} AND rowid > ?1 ORDER BY rowid LIMIT ?2",
+            FileStatus::Pending.condition()
+        ))?;
+        let rows = statement.query_map(params![row, limit as i64], |row| {
+            Ok((row.get("rowid")?, FileRecord::from_row(row)?))
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Marks the matching files as not uploaded, so that they are uploaded again on the next run
+    ///
+    /// Files are only uploaded again to the destinations that failed,
+    /// or to all of them if none did, as when requeueing uploaded files.
+    /// Returns the number of requeued files.
+    pub fn requeue(&self, filter: &FileFilter) -> Result<usize> {
+        let (condition, parameters) = filter.condition();
+        self.in_transaction(|| {
+            self.connection.execute(
+                &format!(
+                    "INSERT INTO FileEvent (path, kind) SELECT path, 'requeued' FROM File WHERE {}",
+                    condition
+                ),
+                &parameters,
+            )?;
+            // Before the failures are reset, which would make every destination look successful
+            self.connection.execute(
+                &format!(
+                    "UPDATE FileDestination SET uploaded_date = NULL, failed_date = NULL
+                     WHERE path IN (SELECT path FROM File WHERE {})
+                       AND path NOT IN (SELECT path FROM FileDestination WHERE failed_date IS NOT NULL)",
+                    condition
+                ),
+                &parameters,
+            )?;
+            self.connection.execute(
+                &format!(
+                    "UPDATE FileDestination SET uploaded_date = NULL, failed_date = NULL
+                     WHERE path IN (SELECT path FROM File WHERE {}) AND failed_date IS NOT NULL",
+                    condition
+                ),
+                &parameters,
+            )?;
+            Ok(self.connection.execute(
+                &format!(
+                    "UPDATE File SET uploaded_date = NULL, failed_date = NULL WHERE {}",
+                    condition
+                ),
+                &parameters,
+            )?)
+        })
+    }
+
+    /// Removes the matching files and their history
+    ///
+    /// A forgotten file is handled as a new one if it shows up again.
+    /// Returns the number of removed files.
+    pub fn forget(&self, filter: &FileFilter) -> Result<usize> {
+        let (condition, parameters) = filter.condition();
+        self.in_transaction(|| {
+            self.connection.execute(
+                &format!(
+                    "DELETE FROM FileEvent WHERE path IN (SELECT path FROM File WHERE {})",
+                    condition
+                ),
+                &parameters,
+            )?;
+            self.connection.execute(
+                &format!(
+                    "DELETE FROM FileDestination WHERE path IN (SELECT path FROM File WHERE {})",
+                    condition
+                ),
+                &parameters,
+            )?;
+            Ok(self.connection.execute(
+                &format!("DELETE FROM File WHERE {}", condition),
+                &parameters,
+            )?)
+        })
+    }
+
+    pub fn get_file<P: AsRef<Path>>(&self, path: P) -> Result<Option<FileRecord>> {
         let mut statement = self
             .connection
-            .prepare_cached("UPDATE File SET uploaded_date = DATETIME('now') WHERE path = (?1)")?;
-        statement.execute(&[file.full_path.to_str().unwrap()])?;
+            .prepare_cached("SELECT * FROM File WHERE path = (?1)")?;
+        let mut rows = statement.query_map(&[StoredPath(path.as_ref())], FileRecord::from_row)?;
+        Ok(rows.next().transpose()?)
+    }
+
+    pub fn file_events<P: AsRef<Path>>(&self, path: P) -> Result<Vec<FileEvent>> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT date, kind, detail FROM FileEvent WHERE path = (?1) ORDER BY date, id",
+        )?;
+        let rows = statement.query_map(&[StoredPath(path.as_ref())], |row| {
+            Ok(FileEvent {
+                date: row.get("date")?,
+                kind: row.get("kind")?,
+                detail: row.get("detail")?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn file_destinations<P: AsRef<Path>>(&self, path: P) -> Result<Vec<DestinationRecord>> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT * FROM FileDestination WHERE path = (?1) ORDER BY destination",
+        )?;
+        let rows = statement.query_map(&[StoredPath(path.as_ref())], |row| {
+            Ok(DestinationRecord {
+                destination: row.get("destination")?,
+                uploaded_date: row.get("uploaded_date")?,
+                failed_date: row.get("failed_date")?,
+                attempts: row.get("attempts")?,
+                last_error: row.get("last_error")?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Destinations the file was uploaded to, so far
+    pub fn uploaded_destinations<P: AsRef<Path>>(&self, path: P) -> Result<Vec<String>> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT destination FROM FileDestination
+             WHERE path = (?1) AND uploaded_date IS NOT NULL ORDER BY destination",
+        )?;
+        let rows = statement.query_map(&[StoredPath(path.as_ref())], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn in_transaction<T, F: FnOnce() -> Result<T>>(&self, f: F) -> Result<T> {
+        self.connection.execute_batch("BEGIN")?;
+        match f() {
+            Ok(result) => {
+                self.connection.execute_batch("COMMIT")?;
+                Ok(result)
+            }
+            Err(err) => {
+                self.connection.execute_batch("ROLLBACK")?;
+                Err(err)
+            }
+        }
+    }
+
+    fn add_event(&self, path: &Path, kind: &str, detail: Option<&str>) -> Result<()> {
+        let mut statement = self
+            .connection
+            .prepare_cached("INSERT INTO FileEvent (path, kind, detail) VALUES (?1, ?2, ?3)")?;
+        statement.execute(params![StoredPath(path), kind, detail])?;
         Ok(())
     }
 
@@ -89,35 +560,156 @@ impl Database {
     //        tx.commit()?;
     //        Ok(())
     //    }
+}
 
-    //    pub fn files_to_upload(&self) -> Result<Vec<File>> {
-    //        let mut statement = self
-    //            .connection
-    //            .prepare_cached("SELECT * FROM File WHERE uploaded_date IS NULL")?;
-    //        let mut rows = statement.query(NO_PARAMS)?;
-    //
-    //        let mut files: Vec<File> = Vec::new();
-    //
-    //        loop {
-    //            match rows.next() {
-    //                Ok(None) => break,
-    //                Ok(Some(row)) => match file_from_row(row) {
-    //                    Ok(file) => {
-    //                        trace!("Loaded file: {:?}", file);
-    //                        files.push(file);
-    //                    }
-    //                    Err(err) => {
-    //                        let file_path: String = row.get_unwrap("path");
-    //                        warn!(
-    //                            "Failed to load file '{}' from DB. Error: {:?}.",
-    //                            file_path, err
-    //                        );
-    //                    }
-    //                },
-    //                Err(err) => warn!("Error looking for files: {:?}", err),
-    //            };
-    //        }
-    //
-    //        Ok(files)
-    //    }
+#[cfg(test)]
+mod tests {
+    use super::{Database, FileFilter, FileStatus};
+    use crate::controller::file::File;
+    use std::path::Path;
+
+    fn database_with_files(paths: &[&str]) -> Database {
+        let db = Database::open(":memory:").unwrap();
+        for path in paths {
+            let file = File {
+                full_path: path.into(),
+                key: path.trim_start_matches('/').into(),
+                uploaded_to: Vec::new(),
+            };
+            db.add_file(&file).unwrap();
+        }
+        db
+    }
+
+    fn file(path: &str) -> File {
+        File {
+            full_path: path.into(),
+            key: path.into(),
+            uploaded_to: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_requeue_resets_matching_files() {
+        let db = database_with_files(&["/a/1", "/a/2", "/b/1"]);
+        db.set_upload_date(&file("/a/1")).unwrap();
+        db.set_upload_failed(&file("/a/2"), "boom").unwrap();
+        db.set_upload_date(&file("/b/1")).unwrap();
+
+        let filter = FileFilter {
+            glob: Some("/a/*".into()),
+            ..Default::default()
+        };
+        assert_eq!(db.requeue(&filter).unwrap(), 2);
+
+        let pending = db
+            .list_files(&FileFilter::with_status(FileStatus::Pending))
+            .unwrap();
+        let paths: Vec<_> = pending.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, vec!["/a/1", "/a/2"]);
+        assert_eq!(pending[0].key.as_deref(), Some("a/1"));
+        assert_eq!(
+            db.file_events("/a/2").unwrap().last().unwrap().kind,
+            "requeued"
+        );
+    }
+
+    #[test]
+    fn test_pending_after_pages_through_pending_files() {
+        let db = database_with_files(&["/a/1", "/a/2", "/a/3", "/a/4"]);
+        db.set_upload_date(&file("/a/2")).unwrap();
+
+        let page = db.pending_after(0, 2).unwrap();
+        let paths: Vec<_> = page.iter().map(|(_, f)| f.path.as_str()).collect();
+        assert_eq!(paths, vec!["/a/1", "/a/3"]);
+        let page = db.pending_after(page[1].0, 2).unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].1.path, "/a/4");
+    }
+
+    #[test]
+    fn test_destinations_are_tracked_separately() {
+        let db = database_with_files(&["/a/1"]);
+        db.set_destination_uploaded(&file("/a/1"), "default")
+            .unwrap();
+        db.set_destination_failed(&file("/a/1"), "dr", "boom")
+            .unwrap();
+        db.set_destination_uploaded(&file("/a/1"), "dr").unwrap();
+
+        let destinations = db.file_destinations("/a/1").unwrap();
+        assert_eq!(destinations.len(), 2);
+        assert_eq!(destinations[1].destination, "dr");
+        assert_eq!(destinations[1].attempts, 2);
+        assert!(destinations[1].uploaded_date.is_some());
+        assert!(destinations[1].failed_date.is_none());
+
+        db.requeue(&FileFilter::default()).unwrap();
+        let destinations = db.file_destinations("/a/1").unwrap();
+        assert!(destinations.iter().all(|d| d.uploaded_date.is_none()));
+
+        // Only the failed destination is uploaded to again
+        db.set_destination_uploaded(&file("/a/1"), "default")
+            .unwrap();
+        db.set_destination_failed(&file("/a/1"), "dr", "boom")
+            .unwrap();
+        db.requeue(&FileFilter::default()).unwrap();
+        assert_eq!(db.uploaded_destinations("/a/1").unwrap(), vec!["default"]);
+        assert!(db.file_destinations("/a/1").unwrap()[1]
+            .failed_date
+            .is_none());
+        db.forget(&FileFilter::default()).unwrap();
+        assert!(db.file_destinations("/a/1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_forget_allows_adding_file_again() {
+        let db = database_with_files(&["/a/1", "/a/2"]);
+        db.set_upload_failed(&file("/a/1"), "boom").unwrap();
+
+        assert!(db.add_file(&file("/a/1")).is_err());
+        assert_eq!(
+            db.forget(&FileFilter::with_status(FileStatus::Failed))
+                .unwrap(),
+            1
+        );
+        assert!(db.file_events("/a/1").unwrap().is_empty());
+        assert!(db.add_file(&file("/a/1")).is_ok());
+        assert_eq!(db.summary().unwrap().total, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_paths_are_stored_losslessly() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        // Both read "/a/caf\u{fffd}" once made valid UTF-8
+        let paths = [
+            OsStr::from_bytes(b"/a/caf\xe9"),
+            OsStr::from_bytes(b"/a/caf\xea"),
+        ];
+        let db = database_with_files(&[]);
+        for path in &paths {
+            let file = File {
+                full_path: path.into(),
+                key: "a/caf".into(),
+                uploaded_to: Vec::new(),
+            };
+            db.add_file(&file).unwrap();
+            assert!(db.is_known(&file).unwrap());
+        }
+        db.set_upload_date(&File {
+            full_path: paths[0].into(),
+            key: "a/caf".into(),
+            uploaded_to: Vec::new(),
+        })
+        .unwrap();
+
+        let pending = db.pending_after(0, 10).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].1.full_path, Path::new(paths[1]));
+        let uploaded = db.get_file(paths[0]).unwrap().unwrap();
+        assert!(uploaded.uploaded_date.is_some());
+        assert_eq!(db.file_events(paths[0]).unwrap().len(), 2);
+    }
 }
@@ -1,5 +1,28 @@
+use std::ffi::OsStr;
 use std::fmt;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+
+use crate::controller::key::{KeyError, KeyPolicy};
+use crate::template::{Template, Values, HOSTNAME};
+
+/// Placeholders available in key templates
+pub const KEY_PLACEHOLDERS: &[&str] = &[
+    "prefix",
+    "relative_path",
+    "dir",
+    "filename",
+    "yyyy",
+    "mm",
+    "dd",
+    "hh",
+    "host",
+];
+
+/// Keeps the name of the tree's root, so that each tree ends up in its own directory
+pub const DEFAULT_KEY_TEMPLATE: &str = "{prefix}/{relative_path}";
 
 /// A file as handled by this program
 ///
@@ -7,11 +30,13 @@ use std::path::PathBuf;
 /// exist. One such situation is a file that has been deleted.
 ///
 /// The base_path is the root of the tree being watched
-/// The key is the path from the base_path. It will be replicated on the bucket
+/// The key is where the file is uploaded on the bucket, built from its path with a `KeyTemplate`
 #[derive(Debug)]
 pub struct File {
     pub full_path: PathBuf,
-    pub key: PathBuf,
+    pub key: String,
+    /// Destinations the file already reached, which aren't uploaded to again
+    pub uploaded_to: Vec<String>,
 }
 
 impl fmt::Display for File {
@@ -19,3 +44,166 @@ impl fmt::Display for File {
         write!(f, "{}", self.full_path.display())
     }
 }
+
+impl File {
+    /// Builds the file found at `full_path` in the tree rooted at `base_path`
+    pub fn new(
+        base_path: &Path,
+        full_path: PathBuf,
+        key_template: &KeyTemplate,
+    ) -> Result<Self, KeyError> {
+        let key = key_template.render(base_path, &full_path)?;
+        Ok(Self {
+            full_path,
+            key,
+            uploaded_to: Vec::new(),
+        })
+    }
+}
+
+/// Which date fills the date placeholders of a key
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyDate {
+    /// The modification time of the file
+    Modified,
+    /// When the file was detected, or scanned
+    Detected,
+}
+
+/// Lays out the keys of the files on the bucket
+#[derive(Clone, Debug)]
+pub struct KeyTemplate {
+    source: String,
+    template: Template,
+    date: KeyDate,
+    policy: KeyPolicy,
+}
+
+impl Default for KeyTemplate {
+    fn default() -> Self {
+        Self::parse(DEFAULT_KEY_TEMPLATE, KeyDate::Modified).unwrap()
+    }
+}
+
+impl fmt::Display for KeyTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl KeyTemplate {
+    pub fn parse(source: &str, date: KeyDate) -> Result<Self, String> {
+        let template = Template::parse(source, KEY_PLACEHOLDERS)?;
+        if !source.contains("{relative_path}") && !source.contains("{filename}") {
+            return Err(format!(
+                "Key template `{}` must contain {{relative_path}} or {{filename}}",
+                source
+            ));
+        }
+        Ok(Self {
+            source: source.into(),
+            template,
+            date,
+            policy: KeyPolicy::default(),
+        })
+    }
+
+    pub fn with_policy(self, policy: KeyPolicy) -> Self {
+        Self { policy, ..self }
+    }
+
+    /// Renders the key of the file at `full_path` in the tree rooted at `base_path`
+    ///
+    /// Names are converted to key segments following the key policy.
+    /// Dates are in UTC. The detection time is used when the modification time can't be read.
+    fn render(&self, base_path: &Path, full_path: &Path) -> Result<String, KeyError> {
+        let relative_path = full_path
+            .strip_prefix(base_path)
+            .map_err(|_| KeyError::OutsideTree)?;
+        let segment = |name: Option<&OsStr>| match name {
+            Some(name) => self.policy.segment(name),
+            None => Ok(String::new()),
+        };
+        let prefix = segment(base_path.file_name())?;
+        let dir = match relative_path.parent() {
+            Some(dir) => self.path_string(dir)?,
+            None => String::new(),
+        };
+        let filename = segment(full_path.file_name())?;
+
+        let date: DateTime<Utc> = match self.date {
+            KeyDate::Modified => fs::metadata(full_path)
+                .and_then(|metadata| metadata.modified())
+                .map_or_else(|_| Utc::now(), DateTime::from),
+            KeyDate::Detected => Utc::now(),
+        };
+
+        let mut values = Values::new();
+        values.insert("prefix", prefix);
+        values.insert("relative_path", self.path_string(relative_path)?);
+        values.insert("dir", dir);
+        values.insert("filename", filename);
+        values.insert("yyyy", date.format("%Y").to_string());
+        values.insert("mm", date.format("%m").to_string());
+        values.insert("dd", date.format("%d").to_string());
+        values.insert("hh", date.format("%H").to_string());
+        values.insert("host", HOSTNAME.clone());
+
+        // Empty placeholders, like `{dir}` for a file at the root of the tree, leave no empty segment
+        let key: Vec<String> = self
+            .template
+            .render(&values)
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(String::from)
+            .collect();
+        self.policy.check(key.join("/"))
+    }
+
+    /// Joins the components of a relative path with `/`
+    fn path_string(&self, path: &Path) -> Result<String, KeyError> {
+        let components = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(self.policy.segment(name)),
+                _ => None,
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(components.join("/"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{File, KeyDate, KeyTemplate};
+    use chrono::Utc;
+    use std::path::Path;
+
+    #[test]
+    fn test_key_templates() {
+        let base_path = Path::new("/data/reports");
+        let full_path = Path::new("/data/reports/2024/q1.csv").to_path_buf();
+
+        let file = File::new(base_path, full_path.clone(), &KeyTemplate::default()).unwrap();
+        assert_eq!(file.key, "reports/2024/q1.csv");
+
+        // The file doesn't exist, so its date is the detection time
+        let template = KeyTemplate::parse(
+            "{prefix}/{yyyy}/{mm}/{dd}/{relative_path}",
+            KeyDate::Modified,
+        )
+        .unwrap();
+        let file = File::new(base_path, full_path.clone(), &template).unwrap();
+        let expected = format!("reports/{}/2024/q1.csv", Utc::now().format("%Y/%m/%d"));
+        assert_eq!(file.key, expected);
+
+        let template = KeyTemplate::parse("archive/{dir}/{filename}", KeyDate::Detected).unwrap();
+        let file = File::new(base_path, full_path, &template).unwrap();
+        assert_eq!(file.key, "archive/2024/q1.csv");
+        let file = File::new(base_path, base_path.join("top.csv"), &template).unwrap();
+        assert_eq!(file.key, "archive/top.csv");
+
+        assert!(KeyTemplate::parse("{prefix}/{yyyy}", KeyDate::Modified).is_err());
+        assert!(KeyTemplate::parse("{prefix}/{year}/{filename}", KeyDate::Modified).is_err());
+    }
+}
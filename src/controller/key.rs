@@ -0,0 +1,217 @@
+//! Turns file names into S3-safe key segments
+//!
+//! Keys always use `/` as separator, whatever the platform.
+//! Names that aren't valid UTF-8 and characters S3 handles badly are handled according to a `KeyPolicy`.
+
+use std::ffi::OsStr;
+use std::fmt;
+
+use unicode_normalization::UnicodeNormalization;
+
+/// S3 doesn't accept longer keys
+pub const MAX_KEY_LENGTH: usize = 1024;
+
+/// Characters S3 recommends avoiding in keys, on top of control characters
+const UNSAFE_CHARACTERS: &[char] = &[
+    '\\', '{', '}', '^', '%', '`', '[', ']', '"', '<', '>', '~', '#', '|',
+];
+
+/// What to do with names that aren't valid UTF-8
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NonUtf8Policy {
+    /// Percent-encode the invalid bytes
+    Escape,
+    /// Replace the invalid bytes with U+FFFD, different names may end up with the same key
+    Lossy,
+    /// Skip the file
+    Reject,
+}
+
+/// What to do with characters S3 handles badly
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnsafeCharPolicy {
+    /// Leave them as they are, apart from `%` when non-UTF-8 bytes are escaped
+    Keep,
+    /// Percent-encode them, `%` included so that keys can be decoded back
+    Escape,
+    /// Skip the file
+    Reject,
+}
+
+#[derive(Clone, Debug)]
+pub struct KeyPolicy {
+    /// Normalize to Unicode NFC, so that names typed on different systems end up with the same key
+    pub nfc: bool,
+    pub non_utf8: NonUtf8Policy,
+    pub unsafe_chars: UnsafeCharPolicy,
+    /// In bytes
+    pub max_length: usize,
+}
+
+impl Default for KeyPolicy {
+    fn default() -> Self {
+        Self {
+            nfc: false,
+            non_utf8: NonUtf8Policy::Escape,
+            unsafe_chars: UnsafeCharPolicy::Keep,
+            max_length: MAX_KEY_LENGTH,
+        }
+    }
+}
+
+/// Why a file can't get a key
+#[derive(Debug, PartialEq)]
+pub enum KeyError {
+    OutsideTree,
+    NotUtf8(String),
+    UnsafeCharacter { name: String, character: char },
+    TooLong { key: String, max_length: usize },
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::OutsideTree => write!(f, "File is outside of the watched directory"),
+            Self::NotUtf8(name) => write!(f, "Name is not valid UTF-8: {}", name),
+            Self::UnsafeCharacter { name, character } => {
+                write!(
+                    f,
+                    "Name contains {:?}, which is unsafe in keys: {}",
+                    character, name
+                )
+            }
+            Self::TooLong { key, max_length } => {
+                write!(f, "Key is longer than {} bytes: {}", max_length, key)
+            }
+        }
+    }
+}
+
+impl KeyPolicy {
+    /// Converts a file or directory name to a key segment
+    pub fn segment(&self, name: &OsStr) -> Result<String, KeyError> {
+        let mut segment = String::new();
+        for chunk in name.as_encoded_bytes().utf8_chunks() {
+            let valid = if self.nfc {
+                chunk.valid().nfc().collect()
+            } else {
+                chunk.valid().to_owned()
+            };
+            for c in valid.chars() {
+                // Escaped bytes would be mistaken for a literal `%XX` otherwise
+                if c == '%' && self.non_utf8 == NonUtf8Policy::Escape {
+                    escape(b"%", &mut segment);
+                    continue;
+                }
+                if !is_unsafe(c) {
+                    segment.push(c);
+                    continue;
+                }
+                match self.unsafe_chars {
+                    UnsafeCharPolicy::Keep => segment.push(c),
+                    UnsafeCharPolicy::Escape => {
+                        let mut buffer = [0; 4];
+                        escape(c.encode_utf8(&mut buffer).as_bytes(), &mut segment)
+                    }
+                    UnsafeCharPolicy::Reject => {
+                        return Err(KeyError::UnsafeCharacter {
+                            name: name.to_string_lossy().into(),
+                            character: c,
+                        })
+                    }
+                }
+            }
+
+            if chunk.invalid().is_empty() {
+                continue;
+            }
+            match self.non_utf8 {
+                NonUtf8Policy::Escape => escape(chunk.invalid(), &mut segment),
+                NonUtf8Policy::Lossy => segment.push(char::REPLACEMENT_CHARACTER),
+                NonUtf8Policy::Reject => {
+                    return Err(KeyError::NotUtf8(name.to_string_lossy().into()))
+                }
+            }
+        }
+        Ok(segment)
+    }
+
+    /// Checks a whole key
+    pub fn check(&self, key: String) -> Result<String, KeyError> {
+        if key.len() > self.max_length {
+            return Err(KeyError::TooLong {
+                key,
+                max_length: self.max_length,
+            });
+        }
+        Ok(key)
+    }
+}
+
+fn is_unsafe(c: char) -> bool {
+    c.is_control() || UNSAFE_CHARACTERS.contains(&c)
+}
+
+fn escape(bytes: &[u8], segment: &mut String) {
+    for byte in bytes {
+        segment.push_str(&format!("%{:02X}", byte));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyError, KeyPolicy, NonUtf8Policy, UnsafeCharPolicy};
+    use std::ffi::OsStr;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_segments() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut policy = KeyPolicy::default();
+        let invalid = OsStr::from_bytes(b"caf\xe9 #1");
+        assert_eq!(policy.segment(invalid).unwrap(), "caf%E9 #1");
+        // Must not collide with the escaped name above
+        assert_eq!(
+            policy.segment(OsStr::new("caf%E9 #1")).unwrap(),
+            "caf%25E9 #1"
+        );
+
+        policy.unsafe_chars = UnsafeCharPolicy::Escape;
+        assert_eq!(policy.segment(invalid).unwrap(), "caf%E9 %231");
+
+        policy.non_utf8 = NonUtf8Policy::Lossy;
+        assert_eq!(policy.segment(invalid).unwrap(), "caf\u{fffd} %231");
+
+        policy.non_utf8 = NonUtf8Policy::Reject;
+        assert!(matches!(policy.segment(invalid), Err(KeyError::NotUtf8(_))));
+    }
+
+    #[test]
+    fn test_segments() {
+        let mut policy = KeyPolicy::default();
+        assert_eq!(policy.segment(OsStr::new("50% #1")).unwrap(), "50%25 #1");
+
+        policy.non_utf8 = NonUtf8Policy::Reject;
+        assert_eq!(policy.segment(OsStr::new("50% #1")).unwrap(), "50% #1");
+
+        policy.unsafe_chars = UnsafeCharPolicy::Escape;
+        assert_eq!(policy.segment(OsStr::new("50% #1")).unwrap(), "50%25 %231");
+
+        policy.unsafe_chars = UnsafeCharPolicy::Reject;
+        assert!(matches!(
+            policy.segment(OsStr::new("a|b")),
+            Err(KeyError::UnsafeCharacter { character: '|', .. })
+        ));
+
+        // "é" as "e" followed by a combining acute accent
+        let decomposed = OsStr::new("cafe\u{301}");
+        assert_eq!(policy.segment(decomposed).unwrap(), "cafe\u{301}");
+        policy.nfc = true;
+        assert_eq!(policy.segment(decomposed).unwrap(), "caf\u{e9}");
+
+        policy.max_length = 3;
+        assert!(policy.check("abc".into()).is_ok());
+        assert!(policy.check("é/b".into()).is_err());
+    }
+}
@@ -1,56 +1,122 @@
-use std::thread::Builder;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crossbeam_channel::{unbounded, Select};
+use crossbeam_channel::{bounded, RecvTimeoutError, Select, Sender};
 use log::{debug, error, info, warn};
 
-mod database;
+pub mod database;
 pub mod error;
 pub mod file;
+pub mod key;
 
-use crate::config::Config;
-use crate::controller::database::{error::Error as DBError, Database};
+use crate::audit::{AuditLog, Entry};
+use crate::config::SyncConfig;
+use crate::controller::database::{error::Error as DBError, Database, FileRecord};
 use crate::controller::error::Result;
-use crate::uploader::Uploader;
-use crate::watcher::FileWatcher;
+use crate::controller::file::{File, KeyTemplate};
+use crate::health;
+use crate::metrics;
+use crate::notifier::{self, Notification};
+use crate::scheduler::Scheduler;
+use crate::server;
+use crate::supervisor::{Factory, Supervisor};
+use crate::uploader::breaker::CircuitBreaker;
+use crate::uploader::error::ErrorKind;
+use crate::uploader::{Outcome, Uploader};
+use crate::watcher::{self, FileWatcher};
 
-pub struct Controller {}
+/// The controller waits for events and handles them quickly, so it should never miss a beat
+const CONTROLLER_STALL_AFTER: Duration = Duration::from_secs(60);
 
-impl Controller {
-    pub fn run(config: Config) -> Result<()> {
-        let (watcher_tx, watcher_rx) = unbounded();
-        let (ctl2upl_tx, ctl2upl_rx) = unbounded();
-        let (upl2ctl_tx, upl2ctl_rx) = unbounded();
+/// Files detected by the watchers and not recorded yet, watchers wait when it's full
+const WATCHER_CHANNEL_CAPACITY: usize = 1000;
 
-        let db = Database::open("db.sqlite3")?;
+/// Outcome of a one-shot sync
+pub struct SyncReport {
+    pub uploaded: usize,
+    pub failed: usize,
+}
 
-        // There's no need to hold handles to the threads,
-        // they are expected to stop when their respective channels will be closed
-        for num in 1..=config.num_uploaders {
-            let uploader = Uploader::new(
-                &config.bucket_name,
-                "eu-west-3",
-                ctl2upl_rx.clone(),
-                upl2ctl_tx.clone(),
-            );
-            Builder::new()
-                .name(format!("uploader {}", num))
-                .spawn(move || uploader.run())?;
+pub struct Controller {
+    db: Database,
+    audit: Option<AuditLog>,
+    notifier: Option<notifier::Handle>,
+    /// Don't record anything in the database, uploaders only log what they would do
+    dry_run: bool,
+}
+
+impl Controller {
+    fn new(config: &SyncConfig, database_path: &str) -> Result<Self> {
+        if let Some(address) = &config.listen_address {
+            server::spawn(address)?;
         }
 
-        for watcher in
-            FileWatcher::create_watchers(&config.watched_dirs, watcher_tx, config.watcher_delay)?
-        {
-            Builder::new()
-                .name(watcher.base_path.display().to_string())
-                .spawn(move || watcher.run())?;
+        let db = Database::open(database_path)?;
+        metrics::PENDING_FILES.set(db.summary()?.pending);
+
+        // Nothing is uploaded in dry-run mode, so there is nothing to audit
+        let audit = match &config.audit_log {
+            Some(path) if !config.dry_run => Some(AuditLog::open(path)?),
+            _ => None,
+        };
+        let notifier = match &config.webhook {
+            Some(webhook) if !config.dry_run => Some(notifier::spawn(webhook.clone())?),
+            _ => None,
+        };
+
+        Ok(Self {
+            db,
+            audit,
+            notifier,
+            dry_run: config.dry_run,
+        })
+    }
+
+    pub fn run(config: SyncConfig, database_path: &str) -> Result<()> {
+        let (watcher_tx, watcher_rx) = bounded(WATCHER_CHANNEL_CAPACITY);
+        let scheduler = Arc::new(Scheduler::new(config.queues.clone(), config.smallest_first));
+        // Uploaders wait for the controller to record their results
+        let (upl2ctl_tx, upl2ctl_rx) = bounded(config.num_uploaders as usize);
+
+        let controller = Self::new(&config, database_path)?;
+        let mut supervisor = Supervisor::new();
+
+        Self::spawn_uploaders(&config, &mut supervisor, &scheduler, upl2ctl_tx)?;
+
+        let watchers = FileWatcher::create_watchers(
+            &config.watched_dirs,
+            &config.key_template,
+            watcher_tx.clone(),
+            config.watcher_delay,
+        )?;
+        let base_paths: Vec<PathBuf> = watchers.iter().map(|w| w.base_path.clone()).collect();
+
+        let mut feeder = Feeder::new(scheduler.clone(), base_paths, config.queue_window);
+        feeder.fill(&controller.db)?;
+
+        for watcher in watchers {
+            let name = watcher.base_path.display().to_string();
+            let factory = Self::watcher_factory(watcher, &config, watcher_tx.clone());
+            supervisor.spawn(&name, factory)?;
         }
 
         let mut sel = Select::new();
         let rcv_from_watcher = sel.recv(&watcher_rx);
         let rcv_from_uploader = sel.recv(&upl2ctl_rx);
+        let heartbeat = health::register("controller", CONTROLLER_STALL_AFTER);
 
         loop {
-            let oper = sel.select();
+            heartbeat.beat();
+            supervisor.check();
+            let oper = match sel.select_timeout(health::HEARTBEAT_INTERVAL) {
+                Ok(oper) => oper,
+                Err(_) => {
+                    // Room may have been freed by an uploader that panicked
+                    controller.feed(&mut feeder);
+                    continue;
+                }
+            };
 
             match oper.index() {
                 i if i == rcv_from_watcher => match oper.recv(&watcher_rx) {
@@ -58,35 +124,768 @@ impl Controller {
                         error!("Failed to receive file from watcher: {}", err);
                         break;
                     }
-                    Ok(file) => match db.add_file(&file) {
-                        Ok(_) => ctl2upl_tx.send(file).unwrap_or_else(|err| {
-                            warn!("Failed to send file to uploader: {}", err)
-                        }),
-                        Err(DBError::FileExists(err)) => {
-                            warn!("Attempted to insert known file: {}", file);
-                            debug!("Failed to add file `{}` to db: {}", file, err);
+                    Ok(file) => {
+                        if !controller.add_file(&file) {
+                            continue;
+                        }
+                        if controller.dry_run {
+                            // Nothing is recorded, so the file can't wait in the database
+                            scheduler.push(file);
+                        } else {
+                            controller.feed(&mut feeder);
                         }
-                        Err(err) => error!("Unexpected database error: {}", err),
-                    },
+                    }
                 },
                 i if i == rcv_from_uploader => match oper.recv(&upl2ctl_rx) {
                     Err(err) => {
                         warn!("Failed to receive from uploader: {}", err);
                         break;
                     }
-                    Ok((file, result)) => match result {
-                        Err(err) => warn!("Failed to upload {}: {}", file, err),
-                        Ok(()) => match db.set_upload_date(&file) {
-                            Ok(()) => info!("Uploaded {}", file),
-                            Err(err) => {
-                                error!("Uploaded file but failed to update database: {}", err)
-                            }
-                        },
-                    },
+                    Ok((file, outcomes)) => {
+                        controller.handle_upload_result(&file, outcomes);
+                        controller.feed(&mut feeder);
+                    }
                 },
                 _ => unreachable!(),
             }
         }
+        scheduler.close();
         Ok(())
     }
+
+    /// Uploads the files of the watched directories that haven't been uploaded yet
+    ///
+    /// This includes files that are already known but pending, for example after a `requeue`.
+    /// Files whose upload failed are left alone.
+    pub fn sync(config: SyncConfig, database_path: &str) -> Result<SyncReport> {
+        let scheduler = Arc::new(Scheduler::new(config.queues.clone(), config.smallest_first));
+        let (upl2ctl_tx, upl2ctl_rx) = bounded(config.num_uploaders as usize);
+
+        let controller = Self::new(&config, database_path)?;
+        let mut supervisor = Supervisor::new();
+
+        Self::spawn_uploaders(&config, &mut supervisor, &scheduler, upl2ctl_tx)?;
+
+        let base_paths = watcher::tree_roots(&config.watched_dirs)?;
+        let mut new_files = 0;
+        for base_path in &base_paths {
+            for file in watcher::scan_tree(base_path, &config.key_template)? {
+                if controller.add_file(&file) {
+                    new_files += 1;
+                    if controller.dry_run {
+                        // Nothing is recorded, so the file can't wait in the database
+                        scheduler.push(file);
+                    }
+                }
+            }
+        }
+        info!("Found {} new file(s)", new_files);
+
+        let direct = if controller.dry_run { new_files } else { 0 };
+        let mut feeder = Feeder::new(scheduler.clone(), base_paths, config.queue_window);
+        let mut supervisor = Some(supervisor);
+        let mut report = SyncReport {
+            uploaded: 0,
+            failed: 0,
+        };
+        loop {
+            if supervisor.is_some() && feeder.fill(&controller.db)? {
+                // Uploaders stop once the queue is drained.
+                // They aren't restarted, so that results stop coming once they're all done.
+                scheduler.close();
+                supervisor = None;
+            }
+            match upl2ctl_rx.recv_timeout(health::HEARTBEAT_INTERVAL) {
+                Ok((file, outcomes)) => {
+                    if controller.handle_upload_result(&file, outcomes) {
+                        report.uploaded += 1;
+                    } else {
+                        report.failed += 1;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some(supervisor) = &mut supervisor {
+                        supervisor.check();
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        // Files never reported back were lost along with their uploader
+        let reported = report.uploaded + report.failed;
+        report.failed += (direct + feeder.fed).saturating_sub(reported);
+        controller.finish();
+        Ok(report)
+    }
+
+    /// Waits for the notifications to be delivered
+    fn finish(self) {
+        if let Some(notifier) = self.notifier {
+            notifier.finish();
+        }
+    }
+
+    fn spawn_uploaders(
+        config: &SyncConfig,
+        supervisor: &mut Supervisor,
+        scheduler: &Arc<Scheduler>,
+        upl2ctl_tx: Sender<(File, Vec<Outcome>)>,
+    ) -> Result<()> {
+        // Shared by all the uploaders, one per destination
+        let breakers: Vec<_> = config
+            .destinations
+            .iter()
+            .map(|(name, _)| Arc::new(CircuitBreaker::new(name)))
+            .collect();
+        for num in 1..=config.num_uploaders {
+            let name = format!("uploader {}", num);
+            let uploader_name = name.clone();
+            let config = config.clone();
+            let scheduler = scheduler.clone();
+            let breakers = breakers.clone();
+            let upl2ctl_tx = upl2ctl_tx.clone();
+
+            supervisor.spawn(
+                &name,
+                Box::new(move || {
+                    let uploader = Uploader::new(
+                        &uploader_name,
+                        &config,
+                        scheduler.clone(),
+                        &breakers,
+                        upl2ctl_tx.clone(),
+                    )?;
+                    Ok(Box::new(move || uploader.run()))
+                }),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Starts with an already built watcher, builds a new one on restarts
+    fn watcher_factory(
+        watcher: FileWatcher,
+        config: &SyncConfig,
+        watcher_tx: Sender<File>,
+    ) -> Factory {
+        let base_path = watcher.base_path.clone();
+        let key_template = config.key_template.clone();
+        let delay = config.watcher_delay;
+        let mut watcher = Some(watcher);
+
+        Box::new(move || {
+            let watcher = match watcher.take() {
+                Some(watcher) => watcher,
+                None => {
+                    FileWatcher::new(&base_path, key_template.clone(), delay, watcher_tx.clone())
+                        .map_err(|err| err.to_string())?
+                }
+            };
+            Ok(Box::new(move || watcher.run()))
+        })
+    }
+
+    /// Records a newly detected file
+    ///
+    /// Returns whether the file is new and should be uploaded.
+    fn add_file(&self, file: &File) -> bool {
+        if self.dry_run {
+            return match self.db.is_known(file) {
+                Ok(true) => {
+                    warn!("Attempted to insert known file: {}", file);
+                    false
+                }
+                Ok(false) => true,
+                Err(err) => {
+                    error!("Unexpected database error: {}", err);
+                    false
+                }
+            };
+        }
+
+        match self.db.add_file(file) {
+            Ok(_) => {
+                metrics::PENDING_FILES.inc();
+                true
+            }
+            Err(DBError::FileExists(err)) => {
+                warn!("Attempted to insert known file: {}", file);
+                debug!("Failed to add file `{}` to db: {}", file, err);
+                false
+            }
+            Err(err) => {
+                error!("Unexpected database error: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Records the results of an upload to each destination, in the database and the audit log
+    ///
+    /// The file only counts as uploaded once all its destinations succeeded.
+    /// Returns whether they did.
+    fn handle_upload_result(&self, file: &File, outcomes: Vec<Outcome>) -> bool {
+        for outcome in &outcomes {
+            self.handle_destination_result(file, outcome);
+        }
+        let errors: Vec<_> = outcomes
+            .iter()
+            .filter_map(|outcome| match &outcome.result {
+                Err(err) => Some((outcome.destination.as_str(), err)),
+                Ok(_) => None,
+            })
+            .collect();
+
+        if self.dry_run {
+            if errors.is_empty() {
+                info!("Would have uploaded {}", file);
+            }
+            return errors.is_empty();
+        }
+
+        metrics::PENDING_FILES.dec();
+        if errors.is_empty() {
+            metrics::FILES_UPLOADED.inc();
+            match self.db.set_upload_date(file) {
+                Ok(()) => {
+                    info!("Uploaded {}", file);
+                    self.notify(Notification::synced(file, &outcomes));
+                }
+                Err(err) => error!("Uploaded file but failed to update database: {}", err),
+            }
+            return true;
+        }
+
+        metrics::FILES_FAILED.inc();
+        let reason = match errors.as_slice() {
+            [(_, err)] if outcomes.len() == 1 => err.to_string(),
+            errors => errors
+                .iter()
+                .map(|(destination, err)| format!("{}: {}", destination, err))
+                .collect::<Vec<_>>()
+                .join("; "),
+        };
+        self.db
+            .set_upload_failed(file, &reason)
+            .unwrap_or_else(|err| error!("Failed to record upload failure in database: {}", err));
+        false
+    }
+
+    /// Records the result of an upload to one of the destinations of a file
+    fn handle_destination_result(&self, file: &File, outcome: &Outcome) {
+        if let Some(audit) = &self.audit {
+            audit
+                .record(&Entry::new(file, outcome))
+                .unwrap_or_else(|err| error!("Failed to write audit log: {}", err));
+        }
+
+        match &outcome.result {
+            Err(err) if self.dry_run => {
+                warn!(
+                    "Would fail to upload {} to {}: {}",
+                    file, outcome.destination, err
+                );
+            }
+            Ok(_) if self.dry_run => (),
+            Err(err) => {
+                match err.kind() {
+                    ErrorKind::Auth => error!(
+                        "Failed to upload {} to {}, check the credentials: {}",
+                        file, outcome.destination, err
+                    ),
+                    kind => warn!(
+                        "Failed to upload {} to {} ({} error): {}",
+                        file, outcome.destination, kind, err
+                    ),
+                }
+                metrics::UPLOAD_ERRORS
+                    .with_label_values(&[&err.kind().to_string()])
+                    .inc();
+                self.db
+                    .set_destination_failed(file, &outcome.destination, &err.to_string())
+                    .unwrap_or_else(|err| {
+                        error!("Failed to record upload failure in database: {}", err)
+                    });
+                // Other errors may well go away when the file is requeued
+                if err.kind() == ErrorKind::Permanent {
+                    self.notify(Notification::new(file, outcome));
+                }
+            }
+            // Only once recorded, so that the webhook never hears of uploads that would be made again
+            Ok(_) => match self.db.set_destination_uploaded(file, &outcome.destination) {
+                Ok(()) => self.notify(Notification::new(file, outcome)),
+                Err(err) => error!("Uploaded file but failed to update database: {}", err),
+            },
+        }
+    }
+
+    fn notify(&self, notification: Notification) {
+        if let Some(notifier) = &self.notifier {
+            notifier.send(notification);
+        }
+    }
+
+    /// Tops the scheduler up, the files stay pending in the database if that fails
+    fn feed(&self, feeder: &mut Feeder) {
+        if let Err(err) = feeder.fill(&self.db) {
+            error!("Failed to load pending files: {}", err);
+        }
+    }
+
+    /// Rebuilds a file recorded in the database
+    ///
+    /// Files recorded before keys were stored get the key they had then, from the tree they are in.
+    /// Returns the path if the file isn't in any of the trees.
+    fn file_from_record(
+        record: FileRecord,
+        base_paths: &[PathBuf],
+    ) -> std::result::Result<File, String> {
+        let FileRecord {
+            path,
+            full_path,
+            key,
+            ..
+        } = record;
+        let base_path = match base_paths.iter().find(|p| full_path.starts_with(p)) {
+            Some(base_path) => base_path,
+            None => return Err(path),
+        };
+
+        match key {
+            Some(key) => Ok(File {
+                full_path,
+                key,
+                uploaded_to: Vec::new(),
+            }),
+            None => File::new(base_path, full_path, &KeyTemplate::default()).map_err(|_| path),
+        }
+    }
+}
+
+/// Hands the pending files of the database to the scheduler, a window at a time
+///
+/// Files wait in the database rather than in memory, so that memory stays flat while uploads are held up.
+/// They are handed out in the order they were recorded, starting after the last one handed out,
+/// so files requeued while the program runs are only picked up on the next start.
+struct Feeder {
+    scheduler: Arc<Scheduler>,
+    base_paths: Vec<PathBuf>,
+    /// Files queued or being uploaded at most
+    window: usize,
+    /// Row of the last file handed out
+    cursor: i64,
+    /// Files handed out so far
+    fed: usize,
+}
+
+impl Feeder {
+    fn new(scheduler: Arc<Scheduler>, base_paths: Vec<PathBuf>, window: usize) -> Self {
+        Self {
+            scheduler,
+            base_paths,
+            window,
+            cursor: 0,
+            fed: 0,
+        }
+    }
+
+    /// Returns whether all the pending files were handed out
+    fn fill(&mut self, db: &Database) -> Result<bool> {
+        loop {
+            let room = self.window.saturating_sub(self.scheduler.in_flight());
+            if room == 0 {
+                return Ok(false);
+            }
+            let records = db.pending_after(self.cursor, room)?;
+            let exhausted = records.len() < room;
+            for (row, record) in records {
+                self.cursor = row;
+                match Controller::file_from_record(record, &self.base_paths) {
+                    Ok(mut file) => {
+                        file.uploaded_to = db.uploaded_destinations(&file.full_path)?;
+                        debug!("Queueing file: {}", file);
+                        self.scheduler.push(file);
+                        self.fed += 1;
+                    }
+                    Err(path) => debug!(
+                        "Pending file is outside of the synced directories: {}",
+                        path
+                    ),
+                }
+            }
+            if exhausted {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Controller;
+    use crate::config::SyncConfig;
+    use crate::controller::database::{Database, DestinationRecord, FileFilter, FileRecord};
+    use crate::metrics;
+    use crate::mock_s3::{MockS3, Operation};
+    use crate::uploader::store::directory::MARKER;
+    use crossbeam_channel::{unbounded, Receiver};
+    use serde_json::Value;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::TempDir;
+    use tiny_http::{Response, Server};
+
+    const BUCKET: &str = "bucket";
+
+    /// A watched directory, `data`, next to the database and the keys file
+    struct Setup {
+        dir: TempDir,
+        s3: MockS3,
+    }
+
+    impl Setup {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            fs::create_dir(dir.path().join("data")).unwrap();
+            fs::write(
+                dir.path().join("keys.toml"),
+                "access_key_id = \"AKIDEXAMPLE\"\nsecret_access_key = \"secret\"\n",
+            )
+            .unwrap();
+            Self {
+                dir,
+                s3: MockS3::start(),
+            }
+        }
+
+        fn write(&self, path: &str, size: usize) -> PathBuf {
+            let path = self.dir.path().join("data").join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let data: Vec<u8> = (0..size).map(|num| (num % 251) as u8).collect();
+            fs::write(&path, data).unwrap();
+            path
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.dir.path().join(name).to_string_lossy().into_owned()
+        }
+
+        fn config(&self) -> SyncConfig {
+            self.s3_config(&["-w", &self.path("data")])
+        }
+
+        /// Uploads to the mock S3, the watched directories being among the extra arguments
+        fn s3_config(&self, extra_args: &[&str]) -> SyncConfig {
+            let endpoint = self.s3.endpoint();
+            let mut args = vec!["-b", BUCKET, "--endpoint-url", &endpoint];
+            args.extend(extra_args);
+            self.config_with(&args)
+        }
+
+        /// Parts are 1 MB
+        fn config_with(&self, extra_args: &[&str]) -> SyncConfig {
+            let keys = self.path("keys.toml");
+            let mut args = vec!["--aws-keys-file", &keys];
+            args.extend(extra_args);
+            let mut config = SyncConfig::for_test(&args);
+            config.upload_part_size = 1;
+            config
+        }
+
+        fn sync(&self) -> (usize, usize) {
+            self.sync_with(self.config())
+        }
+
+        fn sync_with(&self, config: SyncConfig) -> (usize, usize) {
+            let database_path = self.dir.path().join("db.sqlite3");
+            let report = Controller::sync(config, database_path.to_str().unwrap()).unwrap();
+            (report.uploaded, report.failed)
+        }
+
+        fn record(&self, path: &Path) -> FileRecord {
+            let database = Database::open(self.dir.path().join("db.sqlite3")).unwrap();
+            database.get_file(path).unwrap().unwrap()
+        }
+
+        /// Starts a webhook, returning its URL and the events it receives
+        fn webhook(&self) -> (String, Receiver<Value>) {
+            let server = Server::http("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/hook", server.server_addr());
+            let (tx, rx) = unbounded();
+            thread::spawn(move || {
+                for mut request in server.incoming_requests() {
+                    let mut body = String::new();
+                    request.as_reader().read_to_string(&mut body).unwrap();
+                    let payload: Value = serde_json::from_str(&body).unwrap();
+                    for notification in payload["notifications"].as_array().unwrap() {
+                        tx.send(notification.clone()).unwrap();
+                    }
+                    request.respond(Response::empty(200)).unwrap();
+                }
+            });
+            (url, rx)
+        }
+
+        fn destinations(&self, path: &Path) -> Vec<DestinationRecord> {
+            let database = Database::open(self.dir.path().join("db.sqlite3")).unwrap();
+            database.file_destinations(path).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_sync_uploads_files() {
+        let setup = Setup::new();
+        let small = setup.write("a.txt", 10);
+        setup.write("sub/b.txt", 1000);
+        let big = setup.write("big.bin", 2 * 1024 * 1024 + 100);
+
+        assert_eq!(setup.sync(), (3, 0));
+        assert_eq!(
+            setup.s3.keys(BUCKET),
+            vec!["data/a.txt", "data/big.bin", "data/sub/b.txt"]
+        );
+        assert_eq!(setup.s3.requests(Operation::UploadPart), 3);
+        assert_eq!(
+            setup.s3.object(BUCKET, "data/big.bin").unwrap(),
+            fs::read(&big).unwrap()
+        );
+        let record = setup.record(&small);
+        assert!(record.uploaded_date.is_some());
+        assert_eq!(record.key.as_deref(), Some("data/a.txt"));
+
+        // Nothing left to upload
+        assert_eq!(setup.sync(), (0, 0));
+        assert_eq!(setup.s3.requests(Operation::PutObject), 2);
+    }
+
+    #[test]
+    fn test_failure_at_each_multipart_step() {
+        for (operation, error) in &[
+            (
+                Operation::CreateMultipartUpload,
+                "Failed to create multipart upload",
+            ),
+            (Operation::UploadPart, "Failed to upload part 1"),
+            (
+                Operation::CompleteMultipartUpload,
+                "Failed to complete multipart upload",
+            ),
+        ] {
+            let setup = Setup::new();
+            let big = setup.write("big.bin", 1024 * 1024 + 1);
+            setup.s3.fail(*operation, 1, 403, "AccessDenied");
+
+            assert_eq!(setup.sync(), (0, 1), "{:?}", operation);
+            assert!(setup.s3.keys(BUCKET).is_empty());
+            let record = setup.record(&big);
+            assert!(record.failed_date.is_some());
+            assert!(record.last_error.unwrap().starts_with(error));
+            // An upload that was created is aborted
+            let aborted = *operation != Operation::CreateMultipartUpload;
+            assert_eq!(
+                setup.s3.aborted().len(),
+                aborted as usize,
+                "{:?}",
+                operation
+            );
+        }
+    }
+
+    #[test]
+    fn test_dry_run_uploads_and_records_nothing() {
+        let setup = Setup::new();
+        setup.write("a.txt", 10);
+        setup.write("big.bin", 1024 * 1024 + 1);
+        let data = setup.path("data");
+        let config = setup.s3_config(&["-w", &data, "--dry-run"]);
+
+        assert_eq!(setup.sync_with(config), (2, 0));
+        assert!(setup.s3.keys(BUCKET).is_empty());
+        assert_eq!(setup.s3.requests(Operation::PutObject), 0);
+        assert_eq!(setup.s3.requests(Operation::CreateMultipartUpload), 0);
+        let database = Database::open(setup.dir.path().join("db.sqlite3")).unwrap();
+        assert_eq!(database.summary().unwrap().total, 0);
+    }
+
+    #[test]
+    fn test_metrics_are_served_after_sync() {
+        let setup = Setup::new();
+        setup.write("a.txt", 10);
+        setup.write("big.bin", 1024 * 1024 + 1);
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let data = setup.path("data");
+        let config = setup.s3_config(&["-w", &data, "--listen", &address]);
+        // Counters are shared with the tests running alongside
+        let uploaded = metrics::FILES_UPLOADED.get();
+        let bytes = metrics::BYTES_UPLOADED.get();
+
+        assert_eq!(setup.sync_with(config), (2, 0));
+        let body = ureq::get(&format!("http://{}/metrics", address))
+            .call()
+            .unwrap()
+            .into_string()
+            .unwrap();
+        let value = |name: &str| -> u64 {
+            body.lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+        assert!(value("s3_file_sync_files_uploaded_total") >= uploaded + 2);
+        assert!(value("s3_file_sync_bytes_uploaded_total") >= bytes + 1024 * 1024 + 11);
+        assert!(body.contains("s3_file_sync_upload_duration_seconds_count"));
+    }
+
+    #[test]
+    fn test_synced_files_are_notified() {
+        let setup = Setup::new();
+        let (url, events) = setup.webhook();
+        let data = setup.path("data");
+        let config = setup.s3_config(&["-w", &data, "--webhook-url", &url]);
+
+        setup.write("a.txt", 10);
+        assert_eq!(setup.sync_with(config), (1, 0));
+        let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(event["event"], "uploaded");
+        assert_eq!(event["destination"], "default");
+        let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(event["event"], "synced");
+        assert_eq!(event["key"], "data/a.txt");
+        assert!(event["destination"].is_null());
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_only_permanent_failures_are_notified() {
+        let setup = Setup::new();
+        let (url, events) = setup.webhook();
+        let data = setup.path("data");
+        let config = || setup.s3_config(&["-w", &data, "--webhook-url", &url]);
+
+        setup.write("a.txt", 10);
+        setup.s3.fail(Operation::PutObject, 1, 403, "AccessDenied");
+        assert_eq!(setup.sync_with(config()), (0, 1));
+        let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(event["event"], "failed");
+        assert_eq!(event["error_kind"], "permanent");
+
+        // Still failing after its attempts, but it may go through once requeued
+        setup.write("b.txt", 10);
+        setup.s3.fail(Operation::PutObject, 3, 503, "SlowDown");
+        assert_eq!(setup.sync_with(config()), (0, 1));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_transient_error_is_retried() {
+        let setup = Setup::new();
+        let file = setup.write("a.txt", 10);
+        setup.s3.fail(Operation::PutObject, 1, 503, "SlowDown");
+
+        assert_eq!(setup.sync(), (1, 0));
+        assert_eq!(setup.s3.requests(Operation::PutObject), 2);
+        assert_eq!(setup.record(&file).attempts, 1);
+    }
+
+    #[test]
+    fn test_sync_to_directory() {
+        let setup = Setup::new();
+        let small = setup.write("a.txt", 10);
+        let big = setup.write("sub/big.bin", 2 * 1024 * 1024 + 100);
+        let target = setup.dir.path().join("target");
+        fs::create_dir(&target).unwrap();
+        fs::write(target.join(MARKER), "").unwrap();
+        let data = setup.path("data");
+        let config =
+            setup.config_with(&["-w", &data, "--destination-dir", target.to_str().unwrap()]);
+
+        assert_eq!(setup.sync_with(config), (2, 0));
+        assert_eq!(
+            fs::read(target.join("data/a.txt")).unwrap(),
+            fs::read(&small).unwrap()
+        );
+        assert_eq!(
+            fs::read(target.join("data/sub/big.bin")).unwrap(),
+            fs::read(&big).unwrap()
+        );
+        // No temporary file left behind
+        assert_eq!(fs::read_dir(target.join("data/sub")).unwrap().count(), 1);
+        assert!(setup.s3.keys(BUCKET).is_empty());
+        let record = setup.record(&big);
+        assert!(record.uploaded_date.is_some());
+        assert_eq!(record.key.as_deref(), Some("data/sub/big.bin"));
+    }
+
+    #[test]
+    fn test_fan_out_to_several_destinations() {
+        let setup = Setup::new();
+        let small = setup.write("a.txt", 10);
+        let big = setup.write("big.bin", 1024 * 1024 + 1);
+        let mirror = setup.dir.path().join("mirror");
+        fs::create_dir(&mirror).unwrap();
+        fs::write(mirror.join(MARKER), "").unwrap();
+        let config_path = setup.dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            format!(
+                "[[watch]]\ndir = {:?}\ndestinations = [\"default\", \"mirror\"]\n\n\
+                 [[destination]]\nname = \"mirror\"\ndir = {:?}\n",
+                setup.path("data"),
+                mirror.to_string_lossy()
+            ),
+        )
+        .unwrap();
+        let config_path = config_path.to_string_lossy();
+        let config = || setup.s3_config(&["-c", &config_path]);
+
+        assert_eq!(setup.sync_with(config()), (2, 0));
+        assert_eq!(setup.s3.keys(BUCKET), vec!["data/a.txt", "data/big.bin"]);
+        assert_eq!(
+            fs::read(mirror.join("data/big.bin")).unwrap(),
+            fs::read(&big).unwrap()
+        );
+        let destinations = setup.destinations(&small);
+        let names: Vec<_> = destinations
+            .iter()
+            .map(|d| d.destination.as_str())
+            .collect();
+        assert_eq!(names, vec!["default", "mirror"]);
+        assert!(destinations.iter().all(|d| d.uploaded_date.is_some()));
+
+        // A file is only uploaded once all its destinations are
+        let other = setup.write("c.txt", 10);
+        setup.s3.fail(Operation::PutObject, 1, 403, "AccessDenied");
+        assert_eq!(setup.sync_with(config()), (0, 1));
+        assert!(mirror.join("data/c.txt").exists());
+        let record = setup.record(&other);
+        assert!(record.uploaded_date.is_none());
+        assert!(record
+            .last_error
+            .unwrap()
+            .starts_with("default: Failed to put object"));
+        let destinations = setup.destinations(&other);
+        assert!(destinations[0].failed_date.is_some());
+        assert!(destinations[1].uploaded_date.is_some());
+
+        // Once requeued, it's only uploaded again to the destination that failed
+        fs::remove_file(mirror.join("data/c.txt")).unwrap();
+        let database = Database::open(setup.dir.path().join("db.sqlite3")).unwrap();
+        let filter = FileFilter {
+            glob: Some(other.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        assert_eq!(database.requeue(&filter).unwrap(), 1);
+        assert_eq!(setup.sync_with(config()), (1, 0));
+        assert!(setup.s3.object(BUCKET, "data/c.txt").is_some());
+        assert!(!mirror.join("data/c.txt").exists());
+        assert!(setup.record(&other).uploaded_date.is_some());
+    }
 }
@@ -0,0 +1,143 @@
+//! Liveness of the long-running threads
+//!
+//! Each thread registers itself and gets a [`Heartbeat`] it must beat regularly.
+//! A thread is reported as dead once its heartbeat is dropped, which happens when it returns or panics,
+//! and as stalled when it hasn't beaten for longer than it announced.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use log::warn;
+use serde::Serialize;
+
+/// How often threads waiting for work should wake up to beat
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref REGISTRY: Mutex<BTreeMap<String, Subsystem>> = Mutex::new(BTreeMap::new());
+}
+
+struct Subsystem {
+    last_beat: Instant,
+    stall_after: Duration,
+    alive: bool,
+}
+
+/// Handle a thread uses to report it's still working
+///
+/// Dropping it marks the thread as dead.
+pub struct Heartbeat {
+    name: String,
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        if let Some(subsystem) = REGISTRY.lock().unwrap().get_mut(&self.name) {
+            subsystem.last_beat = Instant::now();
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        if let Ok(mut registry) = REGISTRY.lock() {
+            if let Some(subsystem) = registry.get_mut(&self.name) {
+                subsystem.alive = false;
+            }
+        }
+    }
+}
+
+/// Registers a thread, replacing any previous registration with the same name
+///
+/// The thread is considered stalled if it doesn't beat for `stall_after`.
+pub fn register(name: &str, stall_after: Duration) -> Heartbeat {
+    REGISTRY.lock().unwrap().insert(
+        name.into(),
+        Subsystem {
+            last_beat: Instant::now(),
+            stall_after,
+            alive: true,
+        },
+    );
+    Heartbeat { name: name.into() }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Running,
+    Stalled,
+    Dead,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubsystemReport {
+    pub state: State,
+    pub seconds_since_beat: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub healthy: bool,
+    pub subsystems: BTreeMap<String, SubsystemReport>,
+}
+
+pub fn report() -> Report {
+    let registry = REGISTRY.lock().unwrap();
+    let subsystems: BTreeMap<_, _> = registry
+        .iter()
+        .map(|(name, subsystem)| {
+            let elapsed = subsystem.last_beat.elapsed();
+            let state = if !subsystem.alive {
+                State::Dead
+            } else if elapsed > subsystem.stall_after {
+                State::Stalled
+            } else {
+                State::Running
+            };
+            if state != State::Running {
+                warn!("Unhealthy subsystem {}: {:?}", name, state);
+            }
+            let report = SubsystemReport {
+                state,
+                seconds_since_beat: elapsed.as_secs(),
+            };
+            (name.clone(), report)
+        })
+        .collect();
+
+    Report {
+        healthy: subsystems.values().all(|s| s.state == State::Running),
+        subsystems,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{register, report, State};
+    use std::time::Duration;
+
+    #[test]
+    fn test_dropped_heartbeat_is_dead() {
+        let heartbeat = register("test dropped", Duration::from_secs(60));
+        assert_eq!(report().subsystems["test dropped"].state, State::Running);
+
+        drop(heartbeat);
+        let report = report();
+        assert_eq!(report.subsystems["test dropped"].state, State::Dead);
+        assert!(!report.healthy);
+    }
+
+    #[test]
+    fn test_missed_beat_is_stalled() {
+        let heartbeat = register("test stalled", Duration::from_millis(10));
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(report().subsystems["test stalled"].state, State::Stalled);
+
+        heartbeat.beat();
+        assert_eq!(report().subsystems["test stalled"].state, State::Running);
+    }
+}
@@ -0,0 +1,225 @@
+use std::fs::{self, File as FSFile, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use chrono::{Local, NaiveDate};
+use fern::colors::{Color, ColoredLevelConfig};
+use log::{LevelFilter, Record};
+use serde_json::json;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rotation {
+    Never,
+    /// Rotate once the file reaches the given size in bytes
+    Size(u64),
+    /// Rotate on the first write of each day
+    Daily,
+}
+
+pub struct LogConfig {
+    pub level: LevelFilter,
+    /// Levels overriding `level` for a module and its children, ex: `s3_file_sync::uploader`
+    pub modules: Vec<(String, LevelFilter)>,
+    pub file: Option<PathBuf>,
+    pub rotation: Rotation,
+    /// Number of rotated files kept besides the current one
+    pub keep: usize,
+    pub format: LogFormat,
+}
+
+/// Sets up logging to the console and, if configured, to a file
+pub fn setup(config: &LogConfig, console: fern::Output) -> Result<(), fern::InitError> {
+    let mut dispatch = fern::Dispatch::new().level(config.level);
+    for (module, level) in &config.modules {
+        dispatch = dispatch.level_for(module.clone(), *level);
+    }
+
+    let console = match config.format {
+        LogFormat::Text => text_dispatch(true),
+        LogFormat::Json => json_dispatch(),
+    }
+    .chain(console);
+    dispatch = dispatch.chain(console);
+
+    if let Some(path) = &config.file {
+        let file = RotatingFile::open(path, config.rotation, config.keep)?;
+        // Lines are written whole, so that they aren't split across files
+        let writer: Box<dyn Write + Send> = Box::new(LineWriter::new(file));
+        let file = match config.format {
+            LogFormat::Text => text_dispatch(false),
+            LogFormat::Json => json_dispatch(),
+        }
+        .chain(writer);
+        dispatch = dispatch.chain(file);
+    }
+
+    dispatch.apply()?;
+    Ok(())
+}
+
+fn text_dispatch(colored: bool) -> fern::Dispatch {
+    let colors = ColoredLevelConfig::new()
+        .debug(Color::Cyan)
+        .info(Color::Blue)
+        .warn(Color::Yellow)
+        .error(Color::Red);
+
+    fern::Dispatch::new().format(move |out, message, record| {
+        let level = if colored {
+            colors.color(record.level()).to_string()
+        } else {
+            record.level().to_string()
+        };
+        out.finish(format_args!(
+            "[ {} ][ {:5} ][ {:25} ][ {} ] {}",
+            Local::now().format("%Y-%m-%d %H:%M:%S"),
+            level,
+            record.target(),
+            thread_name(),
+            message
+        ))
+    })
+}
+
+fn json_dispatch() -> fern::Dispatch {
+    fern::Dispatch::new().format(|out, message, record: &Record| {
+        let line = json!({
+            "timestamp": Local::now().to_rfc3339(),
+            "level": record.level().to_string(),
+            "target": record.target(),
+            "thread": thread_name(),
+            "message": message.to_string(),
+        });
+        out.finish(format_args!("{}", line))
+    })
+}
+
+fn thread_name() -> String {
+    thread::current().name().unwrap_or("<unnamed>").into()
+}
+
+/// A log file that is moved aside when it gets too big or too old
+///
+/// Rotated files get a numbered suffix, `.1` being the most recent.
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    keep: usize,
+    file: FSFile,
+    size: u64,
+    date: NaiveDate,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, rotation: Rotation, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        let date = metadata
+            .modified()
+            .map(|time| chrono::DateTime::<Local>::from(time).naive_local().date())
+            .unwrap_or_else(|_| Local::now().naive_local().date());
+
+        Ok(Self {
+            path: path.into(),
+            rotation,
+            keep,
+            file,
+            size: metadata.len(),
+            date,
+        })
+    }
+
+    fn should_rotate(&self, incoming: usize) -> bool {
+        match self.rotation {
+            Rotation::Never => false,
+            Rotation::Size(max) => self.size > 0 && self.size + incoming as u64 > max,
+            Rotation::Daily => self.date != Local::now().naive_local().date(),
+        }
+    }
+
+    fn rotated_path(&self, num: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", num));
+        name.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let oldest = self.rotated_path(self.keep);
+            if oldest.exists() {
+                fs::remove_file(&oldest)?;
+            }
+            for num in (1..self.keep).rev() {
+                let path = self.rotated_path(num);
+                if path.exists() {
+                    fs::rename(&path, self.rotated_path(num + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.date = Local::now().naive_local().date();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate(buf.len()) {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RotatingFile, Rotation};
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn test_size_rotation_keeps_given_number_of_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.log");
+        let mut file = RotatingFile::open(&path, Rotation::Size(10), 2).unwrap();
+
+        for line in &["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("test.log.1")).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("test.log.2")).unwrap(),
+            "second\n"
+        );
+        assert!(!dir.path().join("test.log.3").exists());
+    }
+}
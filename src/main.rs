@@ -1,46 +1,82 @@
+use crate::config::Command;
+use crate::controller::database::Database;
 use crate::controller::Controller;
-use fern::colors::{Color, ColoredLevelConfig};
 use log::{error, info};
-use std::thread;
+use std::process;
 
+mod admin;
+mod audit;
 mod config;
 mod controller;
+mod health;
+mod logging;
+mod metrics;
+#[cfg(test)]
+mod mock_s3;
+mod notifier;
+mod scheduler;
+mod server;
+mod supervisor;
+mod template;
+mod throttle;
 mod uploader;
 mod watcher;
 
 fn main() {
     let config = config::Config::from_args();
-    setup_logger().unwrap();
-    info!("Starting S3 File Sync...");
-    info!("{}", config.pretty_string());
 
-    match Controller::run(config) {
-        Ok(_) => info!("Running!"),
-        Err(err) => error!("Failed to start controller: {}", err),
-    }
-}
+    match config.command {
+        Command::Watch(sync_config) => {
+            logging::setup(&config.log, std::io::stdout().into()).unwrap();
+            info!("Starting S3 File Sync...");
+            info!("{}", sync_config.pretty_string());
+
+            match Controller::run(sync_config, &config.database_path) {
+                Ok(_) => info!("Running!"),
+                Err(err) => error!("Failed to start controller: {}", err),
+            }
+        }
+        Command::Sync(sync_config) => {
+            logging::setup(&config.log, std::io::stdout().into()).unwrap();
+            info!("Starting S3 File Sync (one-shot)...");
+            info!("{}", sync_config.pretty_string());
 
-fn setup_logger() -> Result<(), fern::InitError> {
-    let colors = ColoredLevelConfig::new()
-        .debug(Color::Cyan)
-        .info(Color::Blue)
-        .warn(Color::Yellow)
-        .error(Color::Red);
+            match Controller::sync(sync_config, &config.database_path) {
+                Ok(report) if report.failed == 0 => {
+                    info!("Sync done, uploaded {} file(s)", report.uploaded)
+                }
+                Ok(report) => {
+                    error!(
+                        "Sync done, uploaded {} file(s), failed to upload {} file(s)",
+                        report.uploaded, report.failed
+                    );
+                    process::exit(1);
+                }
+                Err(err) => {
+                    error!("Failed to sync: {}", err);
+                    process::exit(1);
+                }
+            }
+        }
+        command => {
+            // Keep stdout clean for the command's output
+            logging::setup(&config.log, std::io::stderr().into()).unwrap();
 
-    fern::Dispatch::new()
-        .format(move |out, message, record| {
-            out.finish(format_args!(
-                "[ {} ][ {:5} ][ {:25} ][ {} ] {}",
-                chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
-                colors.color(record.level()),
-                record.target(),
-                thread::current().name().unwrap(),
-                message
-            ))
-        })
-        .level(log::LevelFilter::Debug)
-        .chain(std::io::stdout())
-        //        .chain(fern::log_file("output.log")?)
-        .apply()?;
-    Ok(())
+            let result = Database::open(&config.database_path)
+                .map_err(admin::error::Error::from)
+                .and_then(|db| match command {
+                    Command::Status { format } => admin::status(&db, format),
+                    Command::List { filter, format } => admin::list(&db, &filter, format),
+                    Command::Show { path, format } => admin::show(&db, &path, format),
+                    Command::Requeue { filter, dry_run } => admin::requeue(&db, &filter, dry_run),
+                    Command::Forget { filter, dry_run } => admin::forget(&db, &filter, dry_run),
+                    Command::Watch(_) | Command::Sync(_) => unreachable!(),
+                });
+
+            if let Err(err) = result {
+                error!("{}", err);
+                process::exit(1);
+            }
+        }
+    }
 }
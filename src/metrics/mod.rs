@@ -0,0 +1,102 @@
+//! Prometheus metrics, exposed over HTTP by the server
+//!
+//! Metrics are registered in the default registry the first time they're used.
+
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_gauge, register_histogram, register_int_counter,
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec, Encoder, Gauge,
+    Histogram, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+
+lazy_static! {
+    pub static ref FILES_DETECTED: IntCounter = register_int_counter!(
+        "s3_file_sync_files_detected_total",
+        "Files created in the watched directories"
+    )
+    .unwrap();
+    pub static ref FILES_UPLOADED: IntCounter = register_int_counter!(
+        "s3_file_sync_files_uploaded_total",
+        "Files successfully uploaded"
+    )
+    .unwrap();
+    pub static ref FILES_FAILED: IntCounter = register_int_counter!(
+        "s3_file_sync_files_failed_total",
+        "Files whose upload failed"
+    )
+    .unwrap();
+    pub static ref UPLOAD_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "s3_file_sync_upload_errors_total",
+        "Failed uploads, by kind of error",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref FILES_DELETED: IntCounter = register_int_counter!(
+        "s3_file_sync_files_deleted_total",
+        "Files removed from the watched directories"
+    )
+    .unwrap();
+    pub static ref BYTES_UPLOADED: IntCounter = register_int_counter!(
+        "s3_file_sync_bytes_uploaded_total",
+        "Bytes sent to S3, including parts of failed uploads"
+    )
+    .unwrap();
+    pub static ref UPLOAD_DURATION: Histogram = register_histogram!(
+        "s3_file_sync_upload_duration_seconds",
+        "Time taken to upload a file",
+        exponential_buckets(0.05, 2.0, 14).unwrap()
+    )
+    .unwrap();
+    pub static ref QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "s3_file_sync_upload_queue_depth",
+        "Files waiting for an uploader"
+    )
+    .unwrap();
+    pub static ref PENDING_FILES: IntGauge = register_int_gauge!(
+        "s3_file_sync_pending_files",
+        "Files recorded in the database but not uploaded yet"
+    )
+    .unwrap();
+    pub static ref WORKER_RESTARTS: IntCounter = register_int_counter!(
+        "s3_file_sync_worker_restarts_total",
+        "Watcher and uploader threads restarted after stopping"
+    )
+    .unwrap();
+    pub static ref CIRCUIT_OPEN: IntGaugeVec = register_int_gauge_vec!(
+        "s3_file_sync_circuit_open",
+        "1 while uploads are paused because a destination can't be reached",
+        &["destination"]
+    )
+    .unwrap();
+    pub static ref LAST_UPLOAD: Gauge = register_gauge!(
+        "s3_file_sync_last_upload_timestamp_seconds",
+        "Unix time of the last successful upload"
+    )
+    .unwrap();
+}
+
+/// Registers all metrics, so that they're exposed before being first updated
+pub fn init() {
+    lazy_static::initialize(&FILES_DETECTED);
+    lazy_static::initialize(&FILES_UPLOADED);
+    lazy_static::initialize(&FILES_FAILED);
+    lazy_static::initialize(&UPLOAD_ERRORS);
+    lazy_static::initialize(&FILES_DELETED);
+    lazy_static::initialize(&BYTES_UPLOADED);
+    lazy_static::initialize(&UPLOAD_DURATION);
+    lazy_static::initialize(&QUEUE_DEPTH);
+    lazy_static::initialize(&PENDING_FILES);
+    lazy_static::initialize(&WORKER_RESTARTS);
+    lazy_static::initialize(&CIRCUIT_OPEN);
+    lazy_static::initialize(&LAST_UPLOAD);
+}
+
+/// Renders all registered metrics in the Prometheus text format
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    let encoder = TextEncoder::new();
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap_or_else(|err| log::warn!("Failed to encode metrics: {}", err));
+    String::from_utf8(buffer).unwrap_or_default()
+}
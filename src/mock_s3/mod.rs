@@ -0,0 +1,271 @@
+//! A stand-in for S3 in tests, serving the requests of the uploaders over HTTP
+//!
+//! Objects are kept in memory and requests aren't authenticated.
+//! The next requests of an operation can be made to fail with an S3 error.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use percent_encoding::percent_decode_str;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Operation {
+    PutObject,
+    CreateMultipartUpload,
+    UploadPart,
+    CompleteMultipartUpload,
+    AbortMultipartUpload,
+    HeadObject,
+}
+
+struct Failure {
+    status: u16,
+    code: &'static str,
+    remaining: usize,
+}
+
+struct Upload {
+    bucket: String,
+    key: String,
+    parts: BTreeMap<i64, Vec<u8>>,
+}
+
+#[derive(Default)]
+struct State {
+    objects: HashMap<(String, String), Vec<u8>>,
+    uploads: HashMap<String, Upload>,
+    upload_count: u64,
+    /// Keys of the aborted multipart uploads
+    aborted: Vec<String>,
+    requests: Vec<Operation>,
+    failures: HashMap<Operation, Failure>,
+}
+
+/// Answered with its status and an S3 error body
+struct S3Error(u16, &'static str);
+
+type Reply = Result<Response<std::io::Cursor<Vec<u8>>>, S3Error>;
+
+pub struct MockS3 {
+    server: Arc<Server>,
+    state: Arc<Mutex<State>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockS3 {
+    pub fn start() -> Self {
+        let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+        let state = Arc::new(Mutex::new(State::default()));
+        let thread = {
+            let server = server.clone();
+            let state = state.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    handle(&state, request);
+                }
+            })
+        };
+        Self {
+            server,
+            state,
+            thread: Some(thread),
+        }
+    }
+
+    /// To be given with `--endpoint-url`
+    pub fn endpoint(&self) -> String {
+        format!("http://{}", self.server.server_addr())
+    }
+
+    /// Answers the next `times` requests of the operation with an error
+    pub fn fail(&self, operation: Operation, times: usize, status: u16, code: &'static str) {
+        self.state.lock().unwrap().failures.insert(
+            operation,
+            Failure {
+                status,
+                code,
+                remaining: times,
+            },
+        );
+    }
+
+    pub fn object(&self, bucket: &str, key: &str) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state.objects.get(&(bucket.into(), key.into())).cloned()
+    }
+
+    /// Keys of the objects of the bucket, sorted
+    pub fn keys(&self, bucket: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut keys: Vec<String> = state
+            .objects
+            .keys()
+            .filter(|(name, _)| name == bucket)
+            .map(|(_, key)| key.clone())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    pub fn aborted(&self) -> Vec<String> {
+        self.state.lock().unwrap().aborted.clone()
+    }
+
+    /// Number of requests received for the operation, failed ones included
+    pub fn requests(&self, operation: Operation) -> usize {
+        let state = self.state.lock().unwrap();
+        state.requests.iter().filter(|&&op| op == operation).count()
+    }
+}
+
+impl Drop for MockS3 {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+fn handle(state: &Mutex<State>, mut request: Request) {
+    let url = request.url().to_owned();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let path = percent_decode_str(path.trim_start_matches('/')).decode_utf8_lossy();
+    let (bucket, key) = path.split_once('/').unwrap_or((&path, ""));
+    let query: HashMap<&str, &str> = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| param.split_once('=').unwrap_or((param, "")))
+        .collect();
+
+    let operation = match (request.method(), key.is_empty()) {
+        (Method::Head, false) => Operation::HeadObject,
+        (Method::Put, false) if query.contains_key("uploadId") => Operation::UploadPart,
+        (Method::Put, false) => Operation::PutObject,
+        (Method::Post, false) if query.contains_key("uploads") => Operation::CreateMultipartUpload,
+        (Method::Post, false) => Operation::CompleteMultipartUpload,
+        (Method::Delete, false) => Operation::AbortMultipartUpload,
+        _ => {
+            request.respond(Response::empty(405)).unwrap();
+            return;
+        }
+    };
+
+    let mut body = Vec::new();
+    request.as_reader().read_to_end(&mut body).unwrap();
+
+    let mut state = state.lock().unwrap();
+    state.requests.push(operation);
+    let reply = match state.failures.get_mut(&operation) {
+        Some(failure) if failure.remaining > 0 => {
+            failure.remaining -= 1;
+            Err(S3Error(failure.status, failure.code))
+        }
+        _ => state.reply(operation, bucket, key, &query, body),
+    };
+    drop(state);
+
+    let response = reply.unwrap_or_else(|S3Error(status, code)| {
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Error><Code>{}</Code><Message>Injected failure</Message></Error>",
+            code
+        );
+        Response::from_data(body).with_status_code(status)
+    });
+    // The client may have given up, which is fine
+    let _ = request.respond(response);
+}
+
+impl State {
+    fn reply(
+        &mut self,
+        operation: Operation,
+        bucket: &str,
+        key: &str,
+        query: &HashMap<&str, &str>,
+        body: Vec<u8>,
+    ) -> Reply {
+        match operation {
+            Operation::HeadObject => match self.objects.get(&(bucket.into(), key.into())) {
+                // tiny_http leaves the body out of HEAD responses
+                Some(data) => Ok(Response::from_data(data.clone()).with_header(e_tag(data))),
+                None => Err(S3Error(404, "NoSuchKey")),
+            },
+            Operation::PutObject => {
+                let e_tag = e_tag(&body);
+                self.objects.insert((bucket.into(), key.into()), body);
+                Ok(Response::from_data(Vec::new()).with_header(e_tag))
+            }
+            Operation::CreateMultipartUpload => {
+                self.upload_count += 1;
+                let upload_id = format!("upload-{}", self.upload_count);
+                self.uploads.insert(
+                    upload_id.clone(),
+                    Upload {
+                        bucket: bucket.into(),
+                        key: key.into(),
+                        parts: BTreeMap::new(),
+                    },
+                );
+                Ok(xml(format!(
+                    "<InitiateMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                    bucket, key, upload_id
+                )))
+            }
+            Operation::UploadPart => {
+                let upload = self.upload(query)?;
+                let part_number = query
+                    .get("partNumber")
+                    .and_then(|number| number.parse().ok())
+                    .ok_or(S3Error(400, "InvalidArgument"))?;
+                let e_tag = e_tag(&body);
+                upload.parts.insert(part_number, body);
+                Ok(Response::from_data(Vec::new()).with_header(e_tag))
+            }
+            Operation::CompleteMultipartUpload => {
+                let upload_id = query.get("uploadId").copied().unwrap_or_default();
+                let upload = self
+                    .uploads
+                    .remove(upload_id)
+                    .ok_or(S3Error(404, "NoSuchUpload"))?;
+                let parts = upload.parts.len();
+                let data = upload.parts.into_values().flatten().collect();
+                self.objects.insert((upload.bucket, upload.key), data);
+                Ok(xml(format!(
+                    "<CompleteMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><ETag>\"mock-{}\"</ETag></CompleteMultipartUploadResult>",
+                    bucket, key, parts
+                )))
+            }
+            Operation::AbortMultipartUpload => {
+                let upload_id = query.get("uploadId").copied().unwrap_or_default();
+                let upload = self
+                    .uploads
+                    .remove(upload_id)
+                    .ok_or(S3Error(404, "NoSuchUpload"))?;
+                self.aborted.push(upload.key);
+                Ok(Response::from_data(Vec::new()).with_status_code(204))
+            }
+        }
+    }
+
+    fn upload(&mut self, query: &HashMap<&str, &str>) -> Result<&mut Upload, S3Error> {
+        let upload_id = query.get("uploadId").copied().unwrap_or_default();
+        self.uploads
+            .get_mut(upload_id)
+            .ok_or(S3Error(404, "NoSuchUpload"))
+    }
+}
+
+fn e_tag(data: &[u8]) -> Header {
+    let value = format!("\"{:x}\"", md5::compute(data));
+    Header::from_bytes("ETag", value).unwrap()
+}
+
+fn xml(body: String) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_data(format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}",
+        body
+    ))
+}
@@ -0,0 +1,319 @@
+//! Notifies a webhook of the uploads
+//!
+//! Notifications are gathered in batches, each batch being POSTed as `{"notifications": [...]}`.
+//! A batch is sent once it's full or its oldest notification has waited for `BATCH_DELAY`.
+//! Failed deliveries are retried with an exponential backoff, then dropped.
+
+use std::io;
+use std::mem;
+use std::thread::{self, Builder, JoinHandle};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use log::{debug, error, warn};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::audit::Event;
+use crate::controller::file::File;
+use crate::health::{self, Heartbeat};
+use crate::uploader::error::ErrorKind;
+use crate::uploader::Outcome;
+
+const BATCH_DELAY: Duration = Duration::from_secs(1);
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Allows for a whole batch of retries
+const NOTIFIER_STALL_AFTER: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Maximum number of notifications per request
+    pub batch_size: usize,
+    /// Number of tries before a batch is dropped
+    pub max_attempts: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Notification {
+    pub event: Event,
+    pub path: String,
+    /// Not set for `synced` events, which are about all the destinations of the file
+    pub destination: Option<String>,
+    pub bucket: Option<String>,
+    pub key: String,
+    pub size: Option<u64>,
+    pub e_tag: Option<String>,
+    pub version_id: Option<String>,
+    pub date: DateTime<Utc>,
+    pub error: Option<String>,
+    pub error_kind: Option<ErrorKind>,
+}
+
+impl Notification {
+    pub fn new(file: &File, outcome: &Outcome) -> Self {
+        let (event, uploaded, error, error_kind) = match &outcome.result {
+            Ok(uploaded) => (Event::Uploaded, Some(uploaded), None, None),
+            Err(err) => (Event::Failed, None, Some(err.to_string()), Some(err.kind())),
+        };
+
+        Self {
+            event,
+            path: file.full_path.to_string_lossy().into(),
+            destination: Some(outcome.destination.clone()),
+            bucket: Some(outcome.bucket.clone()),
+            key: file.key.clone(),
+            size: uploaded.map(|u| u.size),
+            e_tag: uploaded.and_then(|u| u.e_tag.clone()),
+            version_id: uploaded.and_then(|u| u.version_id.clone()),
+            date: outcome.finished,
+            error,
+            error_kind,
+        }
+    }
+
+    /// The file reached all its destinations, the last of them in these outcomes
+    pub fn synced(file: &File, outcomes: &[Outcome]) -> Self {
+        let uploaded = outcomes
+            .iter()
+            .find_map(|outcome| outcome.result.as_ref().ok());
+        Self {
+            event: Event::Synced,
+            path: file.full_path.to_string_lossy().into(),
+            destination: None,
+            bucket: None,
+            key: file.key.clone(),
+            size: uploaded.map(|u| u.size),
+            e_tag: None,
+            version_id: None,
+            date: outcomes
+                .iter()
+                .map(|outcome| outcome.finished)
+                .max()
+                .unwrap_or_else(Utc::now),
+            error: None,
+            error_kind: None,
+        }
+    }
+}
+
+/// Sends notifications to the notifier thread
+pub struct Handle {
+    tx: Sender<Notification>,
+    thread: JoinHandle<()>,
+}
+
+impl Handle {
+    pub fn send(&self, notification: Notification) {
+        self.tx
+            .send(notification)
+            .unwrap_or_else(|err| warn!("Failed to send notification to notifier: {}", err));
+    }
+
+    /// Waits for the pending notifications to be delivered
+    pub fn finish(self) {
+        drop(self.tx);
+        if self.thread.join().is_err() {
+            error!("Notifier panicked, some notifications may have been lost");
+        }
+    }
+}
+
+/// Starts the notifier on its own thread
+pub fn spawn(config: WebhookConfig) -> io::Result<Handle> {
+    let (tx, rx) = unbounded();
+    let notifier = Notifier::new(config, rx);
+    let thread = Builder::new()
+        .name("notifier".into())
+        .spawn(move || notifier.run())?;
+    Ok(Handle { tx, thread })
+}
+
+struct Notifier {
+    config: WebhookConfig,
+    rx: Receiver<Notification>,
+    agent: ureq::Agent,
+    retry_delay: Duration,
+    heartbeat: Heartbeat,
+}
+
+impl Notifier {
+    fn new(config: WebhookConfig, rx: Receiver<Notification>) -> Self {
+        Self {
+            config,
+            rx,
+            agent: ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build(),
+            retry_delay: MIN_RETRY_DELAY,
+            heartbeat: health::register("notifier", NOTIFIER_STALL_AFTER),
+        }
+    }
+
+    fn run(&self) {
+        let mut batch = Vec::new();
+        let mut deadline: Option<Instant> = None;
+
+        loop {
+            self.heartbeat.beat();
+            let timeout = deadline.map_or(health::HEARTBEAT_INTERVAL, |deadline| {
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .min(health::HEARTBEAT_INTERVAL)
+            });
+
+            match self.rx.recv_timeout(timeout) {
+                Ok(notification) => {
+                    if batch.is_empty() {
+                        deadline = Some(Instant::now() + BATCH_DELAY);
+                    }
+                    batch.push(notification);
+                    if batch.len() >= self.config.batch_size {
+                        self.deliver(mem::take(&mut batch));
+                        deadline = None;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                        self.deliver(mem::take(&mut batch));
+                        deadline = None;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    if !batch.is_empty() {
+                        self.deliver(batch);
+                    }
+                    debug!("Channel disconnected, shutting down.");
+                    break;
+                }
+            }
+        }
+    }
+
+    fn deliver(&self, batch: Vec<Notification>) {
+        let body = json!({ "notifications": batch }).to_string();
+        let mut delay = self.retry_delay;
+
+        for attempt in 1..=self.config.max_attempts {
+            self.heartbeat.beat();
+            match self
+                .agent
+                .post(&self.config.url)
+                .set("Content-Type", "application/json")
+                .send_string(&body)
+            {
+                Ok(_) => {
+                    debug!("Delivered {} notification(s)", batch.len());
+                    return;
+                }
+                // The webhook rejected the payload, sending it again won't help
+                Err(ureq::Error::Status(status, _)) if is_permanent(status) => {
+                    error!(
+                        "Webhook rejected {} notification(s) with status {}",
+                        batch.len(),
+                        status
+                    );
+                    return;
+                }
+                Err(err) if attempt < self.config.max_attempts => {
+                    warn!(
+                        "Failed to deliver notifications (attempt {}), retrying in {}s: {}",
+                        attempt,
+                        delay.as_secs_f32(),
+                        err
+                    );
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+                Err(err) => error!(
+                    "Dropping {} notification(s) after {} attempts: {}",
+                    batch.len(),
+                    attempt,
+                    err
+                ),
+            }
+        }
+    }
+}
+
+/// Client errors, except timeouts and rate limiting
+fn is_permanent(status: u16) -> bool {
+    (400..500).contains(&status) && status != 408 && status != 429
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Notification, Notifier, WebhookConfig};
+    use crate::audit::Event;
+    use chrono::Utc;
+    use crossbeam_channel::unbounded;
+    use serde_json::Value;
+    use std::thread;
+    use std::time::Duration;
+    use tiny_http::{Response, Server};
+
+    fn notification(key: &str) -> Notification {
+        Notification {
+            event: Event::Uploaded,
+            path: format!("/data/{}", key),
+            destination: Some("default".into()),
+            bucket: Some("bucket".into()),
+            key: key.into(),
+            size: Some(3),
+            e_tag: Some("\"etag\"".into()),
+            version_id: None,
+            date: Utc::now(),
+            error: None,
+            error_kind: None,
+        }
+    }
+
+    #[test]
+    fn test_notifications_are_batched_and_retried() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", server.server_addr());
+
+        // Fails the first request, then accepts everything
+        let webhook = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for (num, mut request) in server.incoming_requests().take(3).enumerate() {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                bodies.push(serde_json::from_str::<Value>(&body).unwrap());
+                let status = if num == 0 { 503 } else { 200 };
+                request.respond(Response::empty(status)).unwrap();
+            }
+            bodies
+        });
+
+        let (tx, rx) = unbounded();
+        let config = WebhookConfig {
+            url,
+            batch_size: 2,
+            max_attempts: 3,
+        };
+        let mut notifier = Notifier::new(config, rx);
+        notifier.retry_delay = Duration::from_millis(10);
+
+        for key in &["a", "b", "c"] {
+            tx.send(notification(key)).unwrap();
+        }
+        drop(tx);
+        notifier.run();
+
+        let bodies = webhook.join().unwrap();
+        let keys = |body: &Value| -> Vec<String> {
+            body["notifications"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|n| n["key"].as_str().unwrap().to_owned())
+                .collect()
+        };
+        assert_eq!(keys(&bodies[0]), vec!["a", "b"]);
+        assert_eq!(bodies[1], bodies[0]);
+        assert_eq!(keys(&bodies[2]), vec!["c"]);
+        assert_eq!(bodies[2]["notifications"][0]["event"], "uploaded");
+    }
+}
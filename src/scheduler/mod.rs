@@ -0,0 +1,311 @@
+//! Decides which file is uploaded next
+//!
+//! Each watched directory has its own queue, so that a directory receiving many files doesn't starve the others.
+//! Queues with the highest priority are served first.
+//! Queues of the same priority share the uploaders in proportion to their weight, with a smooth weighted round-robin.
+//! Within a queue, files are uploaded in the order they were queued, or smallest first.
+//!
+//! The scheduler also counts the files handed out and not done yet,
+//! so that the controller only feeds it a window of files.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crate::controller::file::File;
+use crate::metrics;
+
+/// How the files of a watched directory are scheduled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QueueSettings {
+    pub priority: u32,
+    /// At least 1
+    pub weight: u32,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            priority: 0,
+            weight: 1,
+        }
+    }
+}
+
+/// Returned once the scheduler is closed and all the files were handed out
+#[derive(Debug, PartialEq)]
+pub struct Closed;
+
+struct Entry {
+    /// The size of the file for smallest-first queues, 0 otherwise
+    rank: u64,
+    seq: u64,
+    file: File,
+}
+
+/// The heap pops the greatest entry, which must be the smallest rank, then the first queued
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (Reverse(self.rank), Reverse(self.seq)).cmp(&(Reverse(other.rank), Reverse(other.seq)))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+struct Queue {
+    dir: Option<PathBuf>,
+    settings: QueueSettings,
+    /// Credit of the weighted round-robin
+    current: i64,
+    files: BinaryHeap<Entry>,
+}
+
+struct State {
+    queues: Vec<Queue>,
+    seq: u64,
+    len: usize,
+    /// Handed out to an uploader and not done yet
+    taken: usize,
+    closed: bool,
+}
+
+impl State {
+    /// Picks the queue to serve among the non-empty ones with the highest priority
+    fn next_queue(&mut self) -> Option<usize> {
+        let priority = self
+            .queues
+            .iter()
+            .filter(|queue| !queue.files.is_empty())
+            .map(|queue| queue.settings.priority)
+            .max()?;
+
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for index in 0..self.queues.len() {
+            let queue = &mut self.queues[index];
+            if queue.files.is_empty() || queue.settings.priority != priority {
+                continue;
+            }
+            queue.current += i64::from(queue.settings.weight);
+            total += i64::from(queue.settings.weight);
+            let current = queue.current;
+            if best.is_none_or(|best| current > self.queues[best].current) {
+                best = Some(index);
+            }
+        }
+        let best = best?;
+        self.queues[best].current -= total;
+        Some(best)
+    }
+}
+
+pub struct Scheduler {
+    smallest_first: bool,
+    state: Mutex<State>,
+    available: Condvar,
+}
+
+impl Scheduler {
+    /// Watched directories are canonicalized, like the watchers do, when they exist
+    ///
+    /// Files outside of all the directories go to a queue with the default settings.
+    pub fn new(dirs: Vec<(PathBuf, QueueSettings)>, smallest_first: bool) -> Self {
+        let queue = |dir, settings| Queue {
+            dir,
+            settings,
+            current: 0,
+            files: BinaryHeap::new(),
+        };
+        let mut queues: Vec<Queue> = dirs
+            .into_iter()
+            .map(|(dir, settings)| queue(Some(dir.canonicalize().unwrap_or(dir)), settings))
+            .collect();
+        queues.push(queue(None, QueueSettings::default()));
+
+        Self {
+            smallest_first,
+            state: Mutex::new(State {
+                queues,
+                seq: 0,
+                len: 0,
+                taken: 0,
+                closed: false,
+            }),
+            available: Condvar::new(),
+        }
+    }
+
+    /// The queue of the deepest directory the file is in
+    fn queue_index(queues: &[Queue], path: &Path) -> usize {
+        queues
+            .iter()
+            .enumerate()
+            .filter_map(|(index, queue)| match &queue.dir {
+                Some(dir) if path.starts_with(dir) => Some((index, dir.components().count())),
+                _ => None,
+            })
+            .max_by_key(|(_, depth)| *depth)
+            .map_or(queues.len() - 1, |(index, _)| index)
+    }
+
+    pub fn push(&self, file: File) {
+        let rank = if self.smallest_first {
+            fs::metadata(&file.full_path).map_or(0, |metadata| metadata.len())
+        } else {
+            0
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let index = Self::queue_index(&state.queues, &file.full_path);
+        let seq = state.seq;
+        state.seq += 1;
+        state.queues[index].files.push(Entry { rank, seq, file });
+        state.len += 1;
+        metrics::QUEUE_DEPTH.set(state.len as i64);
+        drop(state);
+        self.available.notify_one();
+    }
+
+    /// Waits at most `timeout` for a file
+    ///
+    /// `done` must be called once the file is dealt with.
+    pub fn pop(&self, timeout: Duration) -> Result<Option<File>, Closed> {
+        let mut state = self.state.lock().unwrap();
+        if state.len == 0 && !state.closed {
+            state = self.available.wait_timeout(state, timeout).unwrap().0;
+        }
+        match state.next_queue() {
+            Some(index) => {
+                let entry = state.queues[index].files.pop().unwrap();
+                state.len -= 1;
+                state.taken += 1;
+                metrics::QUEUE_DEPTH.set(state.len as i64);
+                Ok(Some(entry.file))
+            }
+            None if state.closed => Err(Closed),
+            None => Ok(None),
+        }
+    }
+
+    /// Called by uploaders once a file they took is dealt with, even if they panicked
+    pub fn done(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.taken = state.taken.saturating_sub(1);
+    }
+
+    /// Files queued or being uploaded
+    pub fn in_flight(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.len + state.taken
+    }
+
+    /// No more files will be pushed, uploaders stop once the queues are drained
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.available.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Closed, QueueSettings, Scheduler};
+    use crate::controller::file::File;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    fn file(path: &Path) -> File {
+        File {
+            full_path: path.into(),
+            key: path.to_string_lossy().into(),
+            uploaded_to: Vec::new(),
+        }
+    }
+
+    fn pop_dirs(scheduler: &Scheduler, count: usize) -> Vec<PathBuf> {
+        (0..count)
+            .map(|_| {
+                let file = scheduler.pop(Duration::from_millis(1)).unwrap().unwrap();
+                file.full_path.parent().unwrap().to_path_buf()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_queues_are_served_by_priority_then_weight() {
+        let settings = |priority, weight| QueueSettings { priority, weight };
+        let scheduler = Scheduler::new(
+            vec![
+                ("/noisy".into(), settings(0, 1)),
+                ("/quiet".into(), settings(0, 2)),
+                ("/urgent".into(), settings(1, 1)),
+            ],
+            false,
+        );
+        for num in 0..100 {
+            scheduler.push(file(&Path::new("/noisy").join(num.to_string())));
+        }
+        for num in 0..4 {
+            scheduler.push(file(&Path::new("/quiet").join(num.to_string())));
+        }
+        scheduler.push(file(Path::new("/urgent/now")));
+        scheduler.push(file(Path::new("/elsewhere/file")));
+
+        assert_eq!(pop_dirs(&scheduler, 1), vec![Path::new("/urgent")]);
+        // Quiet gets twice as many turns as noisy, and the files outside of the watched directories one
+        let dirs = pop_dirs(&scheduler, 7);
+        let quiet = dirs
+            .iter()
+            .filter(|dir| dir.as_path() == Path::new("/quiet"))
+            .count();
+        assert_eq!(quiet, 4);
+        // Files are handed out in the order they were queued
+        let file = scheduler.pop(Duration::from_millis(1)).unwrap().unwrap();
+        assert_eq!(file.full_path, Path::new("/noisy/2"));
+
+        scheduler.close();
+        // Closing lets the uploaders drain the queues
+        for _ in 0..97 {
+            assert!(scheduler.pop(Duration::from_millis(1)).unwrap().is_some());
+        }
+        assert_eq!(scheduler.pop(Duration::from_millis(1)).err(), Some(Closed));
+    }
+
+    #[test]
+    fn test_smallest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let scheduler = Scheduler::new(vec![(dir.path().into(), Default::default())], true);
+        for (name, size) in &[("big", 300), ("small", 1), ("medium", 20)] {
+            let path = dir.path().join(name);
+            fs::write(&path, vec![0; *size]).unwrap();
+            scheduler.push(file(&path));
+        }
+
+        let names: Vec<_> = (0..3)
+            .map(|_| {
+                let file = scheduler.pop(Duration::from_millis(1)).unwrap().unwrap();
+                file.full_path.file_name().unwrap().to_owned()
+            })
+            .collect();
+        assert_eq!(names, vec!["small", "medium", "big"]);
+        assert_eq!(scheduler.in_flight(), 3);
+        scheduler.done();
+        assert_eq!(scheduler.in_flight(), 2);
+        assert!(scheduler.pop(Duration::from_millis(1)).unwrap().is_none());
+    }
+}
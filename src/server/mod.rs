@@ -0,0 +1,44 @@
+use std::io;
+use std::thread::Builder;
+
+use log::{debug, info, warn};
+use tiny_http::{Header, Request, Response, Server};
+
+use crate::health;
+use crate::metrics;
+
+/// Starts the HTTP server exposing `/metrics` and `/healthz` on its own thread
+pub fn spawn(address: &str) -> io::Result<()> {
+    metrics::init();
+    let server = Server::http(address).map_err(|err| io::Error::other(err.to_string()))?;
+    info!("Serving metrics and health on http://{}", address);
+
+    Builder::new().name("http server".into()).spawn(move || {
+        for request in server.incoming_requests() {
+            handle_request(request);
+        }
+    })?;
+    Ok(())
+}
+
+fn handle_request(request: Request) {
+    debug!("{} {}", request.method(), request.url());
+    let response = match request.url() {
+        "/healthz" => {
+            let report = health::report();
+            let status = if report.healthy { 200 } else { 503 };
+            Response::from_string(serde_json::to_string(&report).unwrap_or_default())
+                .with_status_code(status)
+                .with_header("Content-Type: application/json".parse::<Header>().unwrap())
+        }
+        "/metrics" => Response::from_string(metrics::render()).with_header(
+            "Content-Type: text/plain; version=0.0.4"
+                .parse::<Header>()
+                .unwrap(),
+        ),
+        _ => Response::from_string("Not found").with_status_code(404),
+    };
+    request
+        .respond(response)
+        .unwrap_or_else(|err| warn!("Failed to send HTTP response: {}", err));
+}
@@ -0,0 +1,166 @@
+//! Keeps the worker threads running
+//!
+//! Workers are built by a factory, so that they can be built again when their thread exits or panics.
+//! Restarts are delayed with an exponential backoff, to avoid spinning when a worker can't run.
+
+use std::any::Any;
+use std::io;
+use std::thread::{Builder, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+
+use crate::metrics;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// The body of a worker thread
+pub type Job = Box<dyn FnOnce() + Send>;
+
+/// Builds a worker, ready to run on its own thread
+pub type Factory = Box<dyn FnMut() -> Result<Job, String>>;
+
+struct Worker {
+    name: String,
+    factory: Factory,
+    handle: Option<JoinHandle<()>>,
+    started: Instant,
+    backoff: Duration,
+    restart_at: Option<Instant>,
+}
+
+impl Worker {
+    fn start(&mut self) -> Result<(), String> {
+        let job = (self.factory)()?;
+        let handle = Builder::new()
+            .name(self.name.clone())
+            .spawn(job)
+            .map_err(|err| err.to_string())?;
+        self.handle = Some(handle);
+        self.started = Instant::now();
+        self.restart_at = None;
+        Ok(())
+    }
+
+    fn schedule_restart(&mut self) {
+        // A worker that ran for a while before stopping isn't failing in a loop
+        if self.started.elapsed() > MAX_BACKOFF {
+            self.backoff = MIN_BACKOFF;
+        }
+        warn!("Restarting {} in {}s", self.name, self.backoff.as_secs());
+        self.restart_at = Some(Instant::now() + self.backoff);
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+#[derive(Default)]
+pub struct Supervisor {
+    workers: Vec<Worker>,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds and starts a worker on a thread called `name`
+    ///
+    /// Failing to start it the first time is an error, as it's likely to be a configuration issue.
+    pub fn spawn(&mut self, name: &str, factory: Factory) -> io::Result<()> {
+        let mut worker = Worker {
+            name: name.into(),
+            factory,
+            handle: None,
+            started: Instant::now(),
+            backoff: MIN_BACKOFF,
+            restart_at: None,
+        };
+        worker
+            .start()
+            .map_err(|err| io::Error::other(format!("Failed to start {}: {}", name, err)))?;
+        self.workers.push(worker);
+        Ok(())
+    }
+
+    /// Reaps the workers that stopped and restarts those whose backoff is over
+    ///
+    /// This is meant to be called regularly from the supervising thread.
+    pub fn check(&mut self) {
+        for worker in &mut self.workers {
+            if worker.handle.as_ref().is_some_and(|h| h.is_finished()) {
+                match worker.handle.take().unwrap().join() {
+                    Ok(()) => error!("{} stopped", worker.name),
+                    Err(panic) => error!("{} panicked: {}", worker.name, panic_message(&panic)),
+                }
+                worker.schedule_restart();
+            }
+
+            if worker.restart_at.is_some_and(|at| at <= Instant::now()) {
+                metrics::WORKER_RESTARTS.inc();
+                match worker.start() {
+                    Ok(()) => info!("Restarted {}", worker.name),
+                    Err(err) => {
+                        error!("Failed to restart {}: {}", worker.name, err);
+                        worker.schedule_restart();
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown cause"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Supervisor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn test_panicked_worker_is_restarted() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let factory_runs = runs.clone();
+
+        let mut supervisor = Supervisor::new();
+        supervisor
+            .spawn(
+                "test worker",
+                Box::new(move || {
+                    let runs = factory_runs.clone();
+                    Ok(Box::new(move || {
+                        if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                            panic!("first run fails");
+                        }
+                    }))
+                }),
+            )
+            .unwrap();
+
+        // Reaps the worker once it has panicked, which schedules its restart
+        while supervisor.workers[0].restart_at.is_none() {
+            sleep(Duration::from_millis(10));
+            supervisor.check();
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        // The first restart happens after the minimum backoff
+        sleep(Duration::from_millis(1100));
+        supervisor.check();
+        while runs.load(Ordering::SeqCst) < 2 {
+            sleep(Duration::from_millis(10));
+        }
+        assert!(supervisor.workers[0].restart_at.is_none());
+    }
+}
@@ -0,0 +1,116 @@
+//! Strings with `{name}` placeholders, filled in for each file
+//!
+//! Placeholders are checked when the template is parsed, so that mistakes are reported at startup.
+//! `{{` and `}}` stand for literal braces.
+
+use std::collections::HashMap;
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    pub static ref HOSTNAME: String = hostname();
+}
+
+/// Values of the placeholders, by name
+pub type Values<'a> = HashMap<&'a str, String>;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Text(String),
+    Placeholder(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    /// Parses a template, only accepting the given placeholders
+    pub fn parse(source: &str, placeholders: &[&str]) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut chars = source.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("Unclosed `{{` in `{}`", source)),
+                        }
+                    }
+                    if !placeholders.contains(&name.as_str()) {
+                        return Err(format!(
+                            "Unknown placeholder {{{}}} in `{}`, expected one of: {}",
+                            name,
+                            source,
+                            placeholders.join(", ")
+                        ));
+                    }
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Placeholder(name));
+                }
+                '}' => return Err(format!("Unmatched `}}` in `{}`, use `}}}}`", source)),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+
+        Ok(Self { segments })
+    }
+
+    /// Fills the placeholders, those without a value are left empty
+    pub fn render(&self, values: &Values) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.as_str(),
+                Segment::Placeholder(name) => values.get(name.as_str()).map_or("", String::as_str),
+            })
+            .collect()
+    }
+}
+
+fn hostname() -> String {
+    hostname::get()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| String::from("localhost"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Template, Values};
+
+    #[test]
+    fn test_render_fills_placeholders() {
+        let template = Template::parse("{{{host}}}/{dir}-{host}", &["host", "dir"]).unwrap();
+        let values: Values = vec![("host", "box".to_string()), ("dir", "logs".to_string())]
+            .into_iter()
+            .collect();
+        assert_eq!(template.render(&values), "{box}/logs-box");
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown_placeholders() {
+        assert!(Template::parse("{hots}", &["host"]).is_err());
+        assert!(Template::parse("{host", &["host"]).is_err());
+        assert!(Template::parse("host}", &["host"]).is_err());
+        assert!(Template::parse("no placeholder", &[]).is_ok());
+    }
+}
@@ -0,0 +1,249 @@
+//! Bandwidth limit shared by all the uploaders
+//!
+//! The limit is a token bucket holding at most one second worth of bytes.
+//! Callers take the bytes they're about to send and wait until the bucket is no longer in debt,
+//! so that a large request delays the following ones instead of being refused.
+//! An optional schedule changes the limit depending on the local time of day.
+
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use chrono::{Local, NaiveTime};
+use futures::sync::mpsc;
+use futures::{Future, Sink, Stream};
+use rusoto_core::ByteStream;
+
+/// Throttled bodies are sent in chunks of this size
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Bytes per second, `None` being unlimited
+pub type Rate = Option<u64>;
+
+/// Parses a rate like `512K`, `5M` or `1G` bytes per second, or `unlimited`
+pub fn parse_rate(rate: &str) -> Result<Rate, String> {
+    if rate == "unlimited" {
+        return Ok(None);
+    }
+    let number = rate.trim_end_matches('B');
+    let (number, unit) = match number.chars().last() {
+        Some('K') => (&number[..number.len() - 1], 1024),
+        Some('M') => (&number[..number.len() - 1], 1024 * 1024),
+        Some('G') => (&number[..number.len() - 1], 1024 * 1024 * 1024),
+        _ => (number, 1),
+    };
+    match number.parse::<u64>() {
+        Ok(number) if number > 0 => Ok(Some(number * unit)),
+        _ => Err(format!(
+            "Invalid rate {}, expected bytes per second with an optional K, M or G unit, or unlimited",
+            rate
+        )),
+    }
+}
+
+fn format_rate(rate: Rate) -> String {
+    match rate {
+        None => String::from("unlimited"),
+        Some(rate) if rate % (1024 * 1024) == 0 => format!("{} MB/s", rate / (1024 * 1024)),
+        Some(rate) if rate % 1024 == 0 => format!("{} KB/s", rate / 1024),
+        Some(rate) => format!("{} B/s", rate),
+    }
+}
+
+/// A time of day range with its own limit
+#[derive(Clone, Debug, PartialEq)]
+pub struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+    rate: Rate,
+}
+
+impl Window {
+    /// Parses `HH:MM-HH:MM=RATE`, the range may span midnight
+    pub fn parse(window: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid window {}, expected HH:MM-HH:MM=RATE", window);
+        let (range, rate) = window.split_once('=').ok_or_else(invalid)?;
+        let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+        let time = |time| NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| invalid());
+        Ok(Self {
+            start: time(start)?,
+            end: time(end)?,
+            rate: parse_rate(rate)?,
+        })
+    }
+
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// The limit depending on the time of day
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    /// Applies outside of the windows
+    pub default: Rate,
+    pub windows: Vec<Window>,
+}
+
+impl Schedule {
+    /// The first window containing the time wins
+    fn rate_at(&self, time: NaiveTime) -> Rate {
+        self.windows
+            .iter()
+            .find(|window| window.contains(time))
+            .map_or(self.default, |window| window.rate)
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.default.is_none() && self.windows.iter().all(|window| window.rate.is_none())
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format_rate(self.default))?;
+        for window in &self.windows {
+            write!(
+                f,
+                ", {}-{} {}",
+                window.start.format("%H:%M"),
+                window.end.format("%H:%M"),
+                format_rate(window.rate)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Takes the bytes from the bucket and returns how long to wait before sending them
+    fn take(&mut self, bytes: usize, rate: u64, now: Instant) -> Duration {
+        let rate = rate as f64;
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.updated = now;
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+pub struct Throttle {
+    schedule: Schedule,
+    bucket: Mutex<Bucket>,
+}
+
+impl Throttle {
+    pub fn new(schedule: Schedule) -> Self {
+        Self {
+            schedule,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    /// Blocks until the bytes can be sent
+    pub fn acquire(&self, bytes: usize) {
+        let rate = match self.schedule.rate_at(Local::now().time()) {
+            Some(rate) => rate,
+            None => return,
+        };
+        let wait = self
+            .bucket
+            .lock()
+            .unwrap()
+            .take(bytes, rate, Instant::now());
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+    }
+
+    /// Wraps a request body so that it's sent no faster than the limit
+    ///
+    /// Chunks are fed by a thread of their own, as the body is read by the runtime sending the request.
+    pub fn body(self: &Arc<Self>, data: Vec<u8>) -> ByteStream {
+        let (mut tx, rx) = mpsc::channel(1);
+        let throttle = self.clone();
+        thread::spawn(move || {
+            let data = Bytes::from(data);
+            let mut start = 0;
+            while start < data.len() {
+                let end = (start + CHUNK_SIZE).min(data.len());
+                throttle.acquire(end - start);
+                // Fails if the request was dropped, there is nobody left to send to
+                tx = match tx.send(data.slice(start, end)).wait() {
+                    Ok(tx) => tx,
+                    Err(_) => return,
+                };
+                start = end;
+            }
+        });
+        ByteStream::new(rx.map_err(|()| io::Error::other("Throttled body was interrupted")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_rate, Bucket, Schedule, Window};
+    use chrono::NaiveTime;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_schedule() {
+        assert_eq!(parse_rate("5M"), Ok(Some(5 * 1024 * 1024)));
+        assert_eq!(parse_rate("512KB"), Ok(Some(512 * 1024)));
+        assert_eq!(parse_rate("unlimited"), Ok(None));
+        assert!(parse_rate("fast").is_err());
+        assert!(Window::parse("9:00-18:00").is_err());
+
+        let schedule = Schedule {
+            default: None,
+            windows: vec![
+                Window::parse("08:00-19:00=5M").unwrap(),
+                Window::parse("22:00-06:00=1M").unwrap(),
+            ],
+        };
+        let rate_at = |time| schedule.rate_at(NaiveTime::parse_from_str(time, "%H:%M").unwrap());
+        assert_eq!(rate_at("12:00"), Some(5 * 1024 * 1024));
+        assert_eq!(rate_at("19:00"), None);
+        assert_eq!(rate_at("23:30"), Some(1024 * 1024));
+        assert_eq!(rate_at("05:59"), Some(1024 * 1024));
+    }
+
+    #[test]
+    fn test_bucket_debt_delays_following_requests() {
+        let now = Instant::now();
+        let mut bucket = Bucket {
+            tokens: 100.0,
+            updated: now,
+        };
+        assert_eq!(bucket.take(100, 100, now), Duration::from_secs(0));
+        assert_eq!(bucket.take(200, 100, now), Duration::from_secs(2));
+        assert_eq!(bucket.take(100, 100, now), Duration::from_secs(3));
+        // Refilled, but never beyond one second worth of bytes
+        let later = now + Duration::from_secs(10);
+        assert_eq!(bucket.take(100, 100, later), Duration::from_secs(0));
+        assert_eq!(bucket.take(100, 100, later), Duration::from_secs(1));
+    }
+}
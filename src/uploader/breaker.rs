@@ -0,0 +1,172 @@
+//! Pauses the uploads while a destination can't be reached
+//!
+//! Each destination has its own breaker, shared by all the uploaders.
+//! Connectivity errors are counted across the uploaders, and any answer from the destination resets the count.
+//! After `TRIP_AFTER` consecutive ones the breaker opens: files going to the destination are held,
+//! and one of the uploaders holding them probes it every `PROBE_INTERVAL` until it answers.
+
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::metrics;
+
+/// Consecutive connectivity errors after which uploads are paused
+const TRIP_AFTER: u32 = 5;
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What an uploader should do while the breaker is open
+#[derive(Debug, PartialEq)]
+pub enum Wait {
+    /// The destination is reachable again
+    Closed,
+    /// It's the caller's turn to check whether the destination is reachable
+    Probe,
+    /// Still open, call `wait` again
+    Open,
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Closed { failures: u32 },
+    Open { probe_at: Instant, probing: bool },
+}
+
+impl State {
+    /// Returns whether the breaker is open after the error
+    fn failure(&mut self, now: Instant) -> bool {
+        match self {
+            Self::Closed { failures } if *failures + 1 >= TRIP_AFTER => {
+                *self = Self::Open {
+                    probe_at: now + PROBE_INTERVAL,
+                    probing: false,
+                };
+                true
+            }
+            Self::Closed { failures } => {
+                *failures += 1;
+                false
+            }
+            Self::Open { .. } => true,
+        }
+    }
+
+    fn wait(&mut self, now: Instant) -> Wait {
+        match self {
+            Self::Closed { .. } => Wait::Closed,
+            Self::Open { probe_at, probing } if !*probing && *probe_at <= now => {
+                *probing = true;
+                Wait::Probe
+            }
+            Self::Open { .. } => Wait::Open,
+        }
+    }
+
+    fn probe_failed(&mut self, now: Instant) {
+        if let Self::Open { .. } = self {
+            *self = Self::Open {
+                probe_at: now + PROBE_INTERVAL,
+                probing: false,
+            };
+        }
+    }
+}
+
+pub struct CircuitBreaker {
+    /// Name of the destination
+    destination: String,
+    state: Mutex<State>,
+    closed: Condvar,
+}
+
+impl CircuitBreaker {
+    pub fn new(destination: &str) -> Self {
+        metrics::CIRCUIT_OPEN
+            .with_label_values(&[destination])
+            .set(0);
+        Self {
+            destination: destination.into(),
+            state: Mutex::new(State::Closed { failures: 0 }),
+            closed: Condvar::new(),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        matches!(*self.state.lock().unwrap(), State::Open { .. })
+    }
+
+    /// Records a connectivity error and returns whether uploads are paused
+    pub fn failure(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let was_open = matches!(*state, State::Open { .. });
+        let open = state.failure(Instant::now());
+        if open && !was_open {
+            warn!(
+                "Destination {} is unreachable after {} consecutive connectivity errors, pausing uploads",
+                self.destination, TRIP_AFTER
+            );
+            metrics::CIRCUIT_OPEN
+                .with_label_values(&[&self.destination])
+                .set(1);
+        }
+        open
+    }
+
+    /// Records an answer from the destination, which closes the breaker
+    pub fn success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let State::Open { .. } = *state {
+            info!(
+                "Destination {} is reachable again, resuming uploads",
+                self.destination
+            );
+            metrics::CIRCUIT_OPEN
+                .with_label_values(&[&self.destination])
+                .set(0);
+            self.closed.notify_all();
+        }
+        *state = State::Closed { failures: 0 };
+    }
+
+    /// Waits at most `timeout` for the breaker to close or for a probe to be due
+    pub fn wait(&self, timeout: Duration) -> Wait {
+        let mut state = self.state.lock().unwrap();
+        match state.wait(Instant::now()) {
+            Wait::Open => (),
+            wait => return wait,
+        }
+        state = self.closed.wait_timeout(state, timeout).unwrap().0;
+        state.wait(Instant::now())
+    }
+
+    pub fn probe_failed(&self) {
+        self.state.lock().unwrap().probe_failed(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{State, Wait, PROBE_INTERVAL, TRIP_AFTER};
+    use std::time::Instant;
+
+    #[test]
+    fn test_breaker_trips_then_probes() {
+        let now = Instant::now();
+        let mut state = State::Closed { failures: 0 };
+        for _ in 1..TRIP_AFTER {
+            assert!(!state.failure(now));
+        }
+        assert_eq!(state.wait(now), Wait::Closed);
+        assert!(state.failure(now));
+
+        assert_eq!(state.wait(now), Wait::Open);
+        let later = now + PROBE_INTERVAL;
+        // A single uploader probes at a time
+        assert_eq!(state.wait(later), Wait::Probe);
+        assert_eq!(state.wait(later), Wait::Open);
+        state.probe_failed(later);
+        assert_eq!(state.wait(later), Wait::Open);
+        assert_eq!(state.wait(later + PROBE_INTERVAL), Wait::Probe);
+    }
+}
@@ -0,0 +1,283 @@
+//! Credentials used to call S3
+//!
+//! Credentials come from the default chain (environment, profile file, container or instance metadata),
+//! a named profile or a file holding static keys.
+//! They can then be used to assume a role with STS, the temporary credentials being refreshed before they expire.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use futures::Future;
+use rusoto_core::credential::{
+    AutoRefreshingProvider, AwsCredentials, ProfileProvider, StaticProvider,
+};
+use rusoto_core::{
+    CredentialsError, DefaultCredentialsProvider, HttpClient, ProvideAwsCredentials, Region,
+};
+use rusoto_s3::S3Client;
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient};
+use serde::Deserialize;
+
+pub const DEFAULT_SESSION_NAME: &str = "s3-file-sync";
+
+/// Where the credentials come from
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    /// Environment, profile file, container or instance metadata, in that order
+    Default,
+    /// A profile of the AWS credentials file
+    Profile(String),
+    Keys(StaticKeys),
+}
+
+/// Keys read from a file
+#[derive(Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StaticKeys {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+/// Keeps the secrets out of the logs
+impl fmt::Debug for StaticKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StaticKeys")
+            .field("access_key_id", &self.access_key_id)
+            .finish()
+    }
+}
+
+impl StaticKeys {
+    /// Reads a TOML file with `access_key_id`, `secret_access_key` and optionally `session_token`
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        toml::from_str(&content).map_err(|err| err.to_string())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssumeRole {
+    role_arn: String,
+    external_id: Option<String>,
+    session_name: String,
+}
+
+impl AssumeRole {
+    pub fn new(
+        role_arn: &str,
+        external_id: Option<&str>,
+        session_name: Option<&str>,
+    ) -> Result<Self, String> {
+        check_role_arn(role_arn)?;
+        let session_name = session_name.unwrap_or(DEFAULT_SESSION_NAME);
+        check_session_name(session_name)?;
+        Ok(Self {
+            role_arn: role_arn.into(),
+            external_id: external_id.map(String::from),
+            session_name: session_name.into(),
+        })
+    }
+}
+
+pub fn check_role_arn(role_arn: &str) -> Result<(), String> {
+    if role_arn.starts_with("arn:") && role_arn.contains(":role/") {
+        Ok(())
+    } else {
+        Err(format!("Invalid role ARN {}", role_arn))
+    }
+}
+
+pub fn check_session_name(name: &str) -> Result<(), String> {
+    if (2..=64).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_+=,.@-".contains(c))
+    {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid session name {}, it must have 2 to 64 letters, digits or _+=,.@- characters",
+            name
+        ))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Credentials {
+    pub source: Source,
+    pub assume_role: Option<AssumeRole>,
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Self {
+            source: Source::Default,
+            assume_role: None,
+        }
+    }
+}
+
+impl fmt::Display for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.source {
+            Source::Default => write!(f, "default chain")?,
+            Source::Profile(name) => write!(f, "profile {}", name)?,
+            Source::Keys(keys) => write!(f, "static keys {}", keys.access_key_id)?,
+        }
+        if let Some(assume_role) = &self.assume_role {
+            write!(f, ", assuming {}", assume_role.role_arn)?;
+        }
+        Ok(())
+    }
+}
+
+impl Credentials {
+    /// Checks and combines the settings, either from the command line or a configuration file
+    pub fn new(
+        profile: Option<&str>,
+        keys_file: Option<&Path>,
+        assume_role: Option<AssumeRole>,
+    ) -> Result<Self, String> {
+        let source = match (profile, keys_file) {
+            (Some(_), Some(_)) => {
+                return Err("A profile and a keys file can't be used together".into())
+            }
+            (Some(name), None) => Source::Profile(name.into()),
+            (None, Some(path)) => Source::Keys(
+                StaticKeys::from_file(path)
+                    .map_err(|err| format!("Invalid keys file {}: {}", path.display(), err))?,
+            ),
+            (None, None) => Source::Default,
+        };
+        Ok(Self {
+            source,
+            assume_role,
+        })
+    }
+
+    pub fn s3_client(&self, region: Region) -> Result<S3Client, String> {
+        let dispatcher = HttpClient::new().map_err(|err| err.to_string())?;
+        let source = SourceProvider::new(&self.source)?;
+        match &self.assume_role {
+            None => Ok(S3Client::new_with(dispatcher, source, region)),
+            Some(assume_role) => {
+                // STS isn't served by S3-compatible endpoints, the region must then be an AWS one
+                let sts_region = match &region {
+                    Region::Custom { name, .. } => name.parse().map_err(|_| {
+                        format!(
+                            "Unknown region {}, STS needs an AWS region to assume a role",
+                            name
+                        )
+                    })?,
+                    region => region.clone(),
+                };
+                let sts = StsClient::new_with(
+                    HttpClient::new().map_err(|err| err.to_string())?,
+                    source,
+                    sts_region,
+                );
+                // Sessions last an hour by default
+                let provider =
+                    AutoRefreshingProvider::new(StsAssumeRoleSessionCredentialsProvider::new(
+                        sts,
+                        assume_role.role_arn.clone(),
+                        assume_role.session_name.clone(),
+                        assume_role.external_id.clone(),
+                        None,
+                        None,
+                        None,
+                    ))
+                    .map_err(|err| err.to_string())?;
+                Ok(S3Client::new_with(dispatcher, provider, region))
+            }
+        }
+    }
+}
+
+type CredentialsFuture = Box<dyn Future<Item = AwsCredentials, Error = CredentialsError> + Send>;
+
+enum SourceProvider {
+    Default(Box<DefaultCredentialsProvider>),
+    Profile(ProfileProvider),
+    Static(StaticProvider),
+}
+
+impl SourceProvider {
+    fn new(source: &Source) -> Result<Self, String> {
+        Ok(match source {
+            Source::Default => {
+                let provider = DefaultCredentialsProvider::new().map_err(|err| err.to_string())?;
+                Self::Default(Box::new(provider))
+            }
+            Source::Profile(name) => {
+                let mut provider = ProfileProvider::new().map_err(|err| err.to_string())?;
+                provider.set_profile(name.as_str());
+                Self::Profile(provider)
+            }
+            Source::Keys(keys) => Self::Static(StaticProvider::new(
+                keys.access_key_id.clone(),
+                keys.secret_access_key.clone(),
+                keys.session_token.clone(),
+                None,
+            )),
+        })
+    }
+}
+
+impl ProvideAwsCredentials for SourceProvider {
+    type Future = CredentialsFuture;
+
+    fn credentials(&self) -> Self::Future {
+        match self {
+            Self::Default(provider) => Box::new(provider.credentials()),
+            Self::Profile(provider) => Box::new(provider.credentials()),
+            Self::Static(provider) => Box::new(provider.credentials()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AssumeRole, Credentials, Source};
+    use rusoto_core::Region;
+    use std::fs;
+
+    #[test]
+    fn test_credentials_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys_file = dir.path().join("keys.toml");
+        fs::write(
+            &keys_file,
+            "access_key_id = \"AKIAEXAMPLE\"\nsecret_access_key = \"secret\"\n",
+        )
+        .unwrap();
+
+        let credentials = Credentials::new(None, Some(&keys_file), None).unwrap();
+        assert!(matches!(credentials.source, Source::Keys(_)));
+        assert!(!format!("{:?}", credentials).contains("secret"));
+        assert!(Credentials::new(Some("archive"), Some(&keys_file), None).is_err());
+
+        let role = "arn:aws:iam::123456789012:role/uploader";
+        assert!(AssumeRole::new(role, Some("ext"), None).is_ok());
+        assert!(AssumeRole::new("uploader", None, None).is_err());
+        assert!(AssumeRole::new(role, None, Some("no spaces")).is_err());
+    }
+
+    #[test]
+    fn test_assume_role_needs_aws_region() {
+        let role = "arn:aws:iam::123456789012:role/uploader";
+        let credentials = Credentials {
+            source: Source::Default,
+            assume_role: Some(AssumeRole::new(role, None, None).unwrap()),
+        };
+        let region = |name: &str| Region::Custom {
+            name: name.into(),
+            endpoint: "http://localhost:9000".into(),
+        };
+        assert!(credentials.s3_client(region("eu-west-3")).is_ok());
+        assert!(credentials.s3_client(region("minio")).is_err());
+        assert!(Credentials::default().s3_client(region("minio")).is_ok());
+    }
+}
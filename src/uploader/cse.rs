@@ -0,0 +1,184 @@
+//! Client-side envelope encryption
+//!
+//! Each object is encrypted with its own random data key, one part at a time, using AES-256-GCM.
+//! The data key is wrapped, either with a local key or for age X25519 recipients,
+//! and stored in the object metadata along with the algorithms and the part size.
+//!
+//! The nonce of a part is 3 zero bytes, the part number on 8 big-endian bytes, then 1 for the last part or 0,
+//! so that parts can't be reordered or dropped without decryption failing.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+
+use crate::uploader::error::{Error, Result};
+use crate::uploader::sse;
+
+pub const CIPHER: &str = "AES-256-GCM";
+pub const KEY_WRAP_LOCAL: &str = "A256GCM";
+pub const KEY_WRAP_AGE: &str = "age-x25519";
+
+/// Names of the metadata entries, S3 prefixes them with `x-amz-meta-`
+pub const META_CIPHER: &str = "cse-cipher";
+pub const META_KEY_WRAP: &str = "cse-key-wrap";
+pub const META_WRAPPED_KEY: &str = "cse-wrapped-key";
+pub const META_PART_SIZE: &str = "cse-part-size";
+
+#[derive(Clone)]
+pub enum ClientSideEncryption {
+    /// Data keys are wrapped with a local 256 bit key
+    KeyFile { key: Vec<u8> },
+    /// Data keys are encrypted with age, any of the recipients can decrypt them
+    Recipients(Vec<age::x25519::Recipient>),
+}
+
+impl ClientSideEncryption {
+    pub fn from_key_file(path: &Path) -> std::result::Result<Self, String> {
+        sse::read_key(path).map(|key| Self::KeyFile { key })
+    }
+
+    pub fn from_recipients<'a>(
+        recipients: impl Iterator<Item = &'a str>,
+    ) -> std::result::Result<Self, String> {
+        let recipients = recipients
+            .map(|recipient| {
+                age::x25519::Recipient::from_str(recipient)
+                    .map_err(|err| format!("Invalid recipient {}: {}", recipient, err))
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(Self::Recipients(recipients))
+    }
+
+    /// Draws a data key for a new object
+    pub fn object_cipher(&self, part_size: usize) -> Result<ObjectCipher> {
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let (key_wrap, wrapped_key) = match self {
+            Self::KeyFile { key } => {
+                let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+                let nonce = Aes256Gcm::generate_nonce(OsRng);
+                let mut wrapped = nonce.to_vec();
+                wrapped.extend(
+                    cipher
+                        .encrypt(&nonce, data_key.as_slice())
+                        .map_err(|_| Error::Encrypt("Failed to wrap data key".into()))?,
+                );
+                (KEY_WRAP_LOCAL, wrapped)
+            }
+            Self::Recipients(recipients) => {
+                let encryptor = age::Encryptor::with_recipients(
+                    recipients.iter().map(|r| r as &dyn age::Recipient),
+                )
+                .map_err(|err| Error::Encrypt(err.to_string()))?;
+                let mut wrapped = Vec::new();
+                let mut writer = encryptor.wrap_output(&mut wrapped)?;
+                writer.write_all(data_key.as_slice())?;
+                writer.finish()?;
+                (KEY_WRAP_AGE, wrapped)
+            }
+        };
+
+        let metadata = [
+            (META_CIPHER, CIPHER.to_owned()),
+            (META_KEY_WRAP, key_wrap.to_owned()),
+            (META_WRAPPED_KEY, base64::encode(&wrapped_key)),
+            (META_PART_SIZE, part_size.to_string()),
+        ]
+        .iter()
+        .map(|(name, value)| (String::from(*name), value.clone()))
+        .collect();
+
+        Ok(ObjectCipher {
+            cipher: Aes256Gcm::new(&data_key),
+            metadata,
+        })
+    }
+}
+
+/// Encrypts the parts of a single object
+pub struct ObjectCipher {
+    cipher: Aes256Gcm,
+    /// To be stored with the object
+    pub metadata: HashMap<String, String>,
+}
+
+impl ObjectCipher {
+    pub fn encrypt_part(&self, part_number: i64, last: bool, plaintext: &[u8]) -> Result<Vec<u8>> {
+        self.cipher
+            .encrypt(&part_nonce(part_number, last), plaintext)
+            .map_err(|_| Error::Encrypt(format!("Failed to encrypt part {}", part_number)))
+    }
+}
+
+fn part_nonce(part_number: i64, last: bool) -> Nonce<<Aes256Gcm as AeadCore>::NonceSize> {
+    let mut nonce = [0; 12];
+    nonce[3..11].copy_from_slice(&(part_number as u64).to_be_bytes());
+    nonce[11] = last as u8;
+    *Nonce::from_slice(&nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{part_nonce, ClientSideEncryption, META_KEY_WRAP, META_WRAPPED_KEY};
+    use aes_gcm::aead::{Aead, KeyInit};
+    use aes_gcm::{Aes256Gcm, Key, Nonce};
+    use std::io::Read;
+    use std::iter;
+
+    /// Decrypts a part, as a tool reading the objects back would
+    fn decrypt_part(data_key: &[u8], part_number: i64, last: bool, ciphertext: &[u8]) -> Vec<u8> {
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(data_key));
+        cipher
+            .decrypt(&part_nonce(part_number, last), ciphertext)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parts_decrypt_with_unwrapped_key() {
+        let key = vec![3; 32];
+        let cse = ClientSideEncryption::KeyFile { key: key.clone() };
+        let object = cse.object_cipher(5).unwrap();
+        let first = object.encrypt_part(1, false, b"hello").unwrap();
+        let last = object.encrypt_part(2, true, b" you").unwrap();
+        assert_eq!(object.metadata[META_KEY_WRAP], "A256GCM");
+
+        let wrapped = base64::decode(&object.metadata[META_WRAPPED_KEY]).unwrap();
+        let (nonce, wrapped) = wrapped.split_at(12);
+        let data_key = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+            .decrypt(Nonce::from_slice(nonce), wrapped)
+            .unwrap();
+
+        assert_eq!(decrypt_part(&data_key, 1, false, &first), b"hello");
+        assert_eq!(decrypt_part(&data_key, 2, true, &last), b" you");
+        // A truncated object doesn't decrypt
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key));
+        assert!(cipher
+            .decrypt(&part_nonce(1, true), first.as_slice())
+            .is_err());
+    }
+
+    #[test]
+    fn test_age_recipients_can_unwrap_key() {
+        let identity = age::x25519::Identity::generate();
+        let recipient = identity.to_public().to_string();
+        let cse = ClientSideEncryption::from_recipients(iter::once(recipient.as_str())).unwrap();
+        assert!(ClientSideEncryption::from_recipients(iter::once("age1nope")).is_err());
+
+        let object = cse.object_cipher(5).unwrap();
+        let part = object.encrypt_part(1, true, b"hello").unwrap();
+        assert_eq!(object.metadata[META_KEY_WRAP], "age-x25519");
+
+        let wrapped = base64::decode(&object.metadata[META_WRAPPED_KEY]).unwrap();
+        let mut data_key = Vec::new();
+        age::Decryptor::new(&wrapped[..])
+            .unwrap()
+            .decrypt(iter::once(&identity as &dyn age::Identity))
+            .unwrap()
+            .read_to_end(&mut data_key)
+            .unwrap();
+        assert_eq!(decrypt_part(&data_key, 1, true, &part), b"hello");
+    }
+}
@@ -1,22 +1,98 @@
 use std::{error::Error as StdError, fmt, io::Error as IOError, result::Result as StdResult};
 
-use rusoto_core::RusotoError;
-use rusoto_s3::{CompleteMultipartUploadError, CreateMultipartUploadError, UploadPartError};
+use serde::Serialize;
 
 pub type Result<T> = StdResult<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
-    CreateMultipartUpload(RusotoError<CreateMultipartUploadError>),
-    UploadPart {
-        part_number: i64,
-        error: RusotoError<UploadPartError>,
-    },
-    CompleteMultipartUpload(RusotoError<CompleteMultipartUploadError>),
+    CreateMultipartUpload(StoreError),
+    UploadPart { part_number: i64, error: StoreError },
+    CompleteMultipartUpload(StoreError),
+    PutObject(StoreError),
+    Encrypt(String),
     Generic(String),
     Read(IOError),
 }
 
+/// A failed request to an object store, as classified by the store
+#[derive(Debug)]
+pub struct StoreError {
+    pub kind: ErrorKind,
+    /// The request didn't reach the store, as opposed to the store rejecting it
+    pub connectivity: bool,
+    pub message: String,
+}
+
+impl StoreError {
+    pub fn new(kind: ErrorKind, message: &str) -> Self {
+        Self {
+            kind,
+            connectivity: false,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// What an upload error means for the next attempts
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// Network errors, timeouts, throttling and server errors, likely to go away on their own
+    Transient,
+    /// The credentials are missing, expired or invalid
+    Auth,
+    /// The store rejected the request and would do it again, like a missing bucket or a denied access
+    Permanent,
+    /// The file couldn't be read
+    LocalIo,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Transient => "transient",
+            Self::Auth => "auth",
+            Self::Permanent => "permanent",
+            Self::LocalIo => "local_io",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::CreateMultipartUpload(err)
+            | Self::UploadPart { error: err, .. }
+            | Self::CompleteMultipartUpload(err)
+            | Self::PutObject(err) => err.kind,
+            // Client-side encryption can only fail because of its settings
+            Self::Encrypt(_) => ErrorKind::Permanent,
+            // The store answered, but not as expected
+            Self::Generic(_) => ErrorKind::Transient,
+            Self::Read(_) => ErrorKind::LocalIo,
+        }
+    }
+
+    /// Whether the request didn't reach the store, as opposed to the store rejecting it
+    pub fn is_connectivity(&self) -> bool {
+        match self {
+            Self::CreateMultipartUpload(err)
+            | Self::UploadPart { error: err, .. }
+            | Self::CompleteMultipartUpload(err)
+            | Self::PutObject(err) => err.connectivity,
+            Self::Encrypt(_) | Self::Generic(_) | Self::Read(_) => false,
+        }
+    }
+}
+
 impl StdError for Error {}
 
 impl fmt::Display for Error {
@@ -31,6 +107,8 @@ impl fmt::Display for Error {
             Self::CompleteMultipartUpload(err) => {
                 write!(f, "Failed to complete multipart upload: {}", err)
             }
+            Self::PutObject(err) => write!(f, "Failed to put object: {}", err),
+            Self::Encrypt(msg) => write!(f, "Failed to encrypt file: {}", msg),
             Self::Read(io_error) => write!(f, "Failed to read file: {}", io_error),
             Self::Generic(msg) => write!(f, "Failed to upload file: {}", msg),
         }
@@ -43,14 +121,24 @@ impl From<IOError> for Error {
     }
 }
 
-impl From<RusotoError<CompleteMultipartUploadError>> for Error {
-    fn from(err: RusotoError<CompleteMultipartUploadError>) -> Self {
-        Self::CompleteMultipartUpload(err)
-    }
-}
-
 impl From<&str> for Error {
     fn from(msg: &str) -> Self {
         Self::Generic(msg.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, ErrorKind, StoreError};
+    use std::io;
+
+    #[test]
+    fn test_error_kinds() {
+        let rejected = StoreError::new(ErrorKind::Permanent, "Access Denied");
+        assert_eq!(Error::PutObject(rejected).kind(), ErrorKind::Permanent);
+        assert_eq!(
+            Error::Read(io::Error::from(io::ErrorKind::NotFound)).kind(),
+            ErrorKind::LocalIo
+        );
+    }
+}
@@ -3,207 +3,622 @@ extern crate md5;
 extern crate rusoto_core;
 extern crate rusoto_s3;
 
-use std::fs::File as FSFile;
-use std::io::Read;
-use std::str::FromStr;
+use std::fs::{self, File as FSFile};
+use std::io::{self, Read};
+use std::iter;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
-use crossbeam_channel::{Receiver, Sender};
+use chrono::{DateTime, Utc};
+use crossbeam_channel::Sender;
 
 use log::{debug, info, warn};
-use rusoto_core::Region;
-use rusoto_s3::{
-    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
-    CompletedPart, CreateMultipartUploadRequest, S3Client, UploadPartRequest, S3,
-};
 
+pub mod breaker;
+pub mod credentials;
+pub mod cse;
 pub mod error;
+pub mod rules;
+pub mod sse;
+pub mod store;
 
+use crate::config::{Destination, SyncConfig, DEFAULT_DESTINATION};
 use crate::controller::file::File;
-use crate::uploader::error::{Error, Result};
+use crate::health::{self, Heartbeat};
+use crate::metrics;
+use crate::scheduler::{Closed, Scheduler};
+use crate::uploader::breaker::{CircuitBreaker, Wait};
+use crate::uploader::credentials::Credentials;
+use crate::uploader::cse::{ClientSideEncryption, ObjectCipher};
+use crate::uploader::error::{Error, ErrorKind, Result};
+use crate::uploader::rules::{ObjectAttributes, RuleSet};
+use crate::uploader::store::directory::DirectoryStore;
+use crate::uploader::store::s3::S3Store;
+use crate::uploader::store::{ObjectStore, Part};
+
+/// Uploaders beat between parts, so this must allow for a slow part upload
+const UPLOADER_STALL_AFTER: Duration = Duration::from_secs(30 * 60);
+/// Uploads failing with a transient error are tried this many times before being reported
+const TRANSIENT_ATTEMPTS: u32 = 3;
+/// Doubles after each attempt
+const TRANSIENT_BACKOFF: Duration = Duration::from_secs(1);
+/// Looked up to check whether a destination is reachable, it doesn't need to exist
+const PROBE_KEY: &str = "s3-file-sync-probe";
+
+/// A completed upload
+#[derive(Debug, Default)]
+pub struct Uploaded {
+    pub size: u64,
+    /// MD5 of the whole file, in hex
+    pub md5: String,
+    pub e_tag: Option<String>,
+    pub version_id: Option<String>,
+}
+
+/// What an uploader reports back to the controller for each destination of a file
+#[derive(Debug)]
+pub struct Outcome {
+    pub uploader: String,
+    /// Name of the destination
+    pub destination: String,
+    pub bucket: String,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub result: Result<Uploaded>,
+}
+
+/// Tells the scheduler a file is done when dropped, including when the upload panics
+struct Taken<'a>(&'a Scheduler);
+
+impl Drop for Taken<'_> {
+    fn drop(&mut self) {
+        self.0.done();
+    }
+}
+
+/// A destination as seen by an uploader
+pub struct Target {
+    pub name: String,
+    pub destination: Destination,
+    /// A store for each of the credentials in use, the first one being for the global credentials
+    pub stores: Vec<(Credentials, Arc<dyn ObjectStore>)>,
+    /// Shared by all the uploaders
+    pub breaker: Arc<CircuitBreaker>,
+}
+
+impl Target {
+    pub fn new(
+        name: &str,
+        destination: &Destination,
+        config: &SyncConfig,
+        breaker: Arc<CircuitBreaker>,
+    ) -> std::result::Result<Target, String> {
+        let mut stores: Vec<(Credentials, Arc<dyn ObjectStore>)> = Vec::new();
+        let directory_store: Option<Arc<dyn ObjectStore>> = match destination {
+            Destination::Directory(path) => Some(Arc::new(DirectoryStore::new(path.clone()))),
+            Destination::S3 { .. } => None,
+        };
+        for credentials in iter::once(&config.credentials).chain(config.rules.all_credentials()) {
+            if stores.iter().any(|(known, _)| known == credentials) {
+                continue;
+            }
+            let store = match (destination, &directory_store) {
+                // Credentials don't matter to a directory
+                (_, Some(store)) => store.clone(),
+                (
+                    Destination::S3 {
+                        bucket_name,
+                        region,
+                    },
+                    None,
+                ) => {
+                    let s3_client = credentials.s3_client(region.clone())?;
+                    Arc::new(S3Store::new(s3_client, bucket_name, config))
+                }
+                _ => unreachable!(),
+            };
+            stores.push((credentials.clone(), store));
+        }
+        Ok(Target {
+            name: name.into(),
+            destination: destination.clone(),
+            stores,
+            breaker,
+        })
+    }
+
+    /// The name of the bucket, or the path of the directory
+    fn bucket(&self) -> String {
+        match &self.destination {
+            Destination::S3 { bucket_name, .. } => bucket_name.clone(),
+            Destination::Directory(path) => path.to_string_lossy().into_owned(),
+        }
+    }
+}
 
 pub struct Uploader {
-    bucket_name: String,
-    s3_client: S3Client,
-    request_payer: Option<String>,
+    name: String,
+    targets: Vec<Target>,
+    credentials: Credentials,
+    /// Files up to this size are uploaded in a single request
     part_size: usize,
-    controller_rx: Receiver<File>,
-    controller_tx: Sender<(File, Result<()>)>,
+    cse: Option<ClientSideEncryption>,
+    rules: RuleSet,
+    scheduler: Arc<Scheduler>,
+    controller_tx: Sender<(File, Vec<Outcome>)>,
+    /// Only log what would be uploaded
+    dry_run: bool,
+    heartbeat: Heartbeat,
 }
 
 impl Uploader {
+    /// Uploads to the destinations of the configuration, with the breaker at the same index
     pub fn new(
-        bucket_name: &str,
-        region_name: &str,
-        controller_rx: Receiver<File>,
-        controller_tx: Sender<(File, Result<()>)>,
-    ) -> Uploader {
-        let region = Region::from_str(region_name).unwrap();
-        let s3_client = S3Client::new(region);
-        let bucket_name: String = bucket_name.into();
+        name: &str,
+        config: &SyncConfig,
+        scheduler: Arc<Scheduler>,
+        breakers: &[Arc<CircuitBreaker>],
+        controller_tx: Sender<(File, Vec<Outcome>)>,
+    ) -> std::result::Result<Uploader, String> {
+        let targets = config
+            .destinations
+            .iter()
+            .zip(breakers)
+            .map(|((name, destination), breaker)| {
+                Target::new(name, destination, config, breaker.clone())
+            })
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(Self::with_targets(
+            name,
+            config,
+            targets,
+            scheduler,
+            controller_tx,
+        ))
+    }
 
+    pub fn with_targets(
+        name: &str,
+        config: &SyncConfig,
+        targets: Vec<Target>,
+        scheduler: Arc<Scheduler>,
+        controller_tx: Sender<(File, Vec<Outcome>)>,
+    ) -> Uploader {
         Uploader {
-            bucket_name,
-            s3_client,
-            request_payer: None,
-            part_size: 1024 * 1024 * 100, // 100 MB
-            controller_rx,
+            name: name.into(),
+            targets,
+            credentials: config.credentials.clone(),
+            part_size: config.upload_part_size as usize * 1024 * 1024,
+            cse: config.cse.clone(),
+            rules: config.rules.clone(),
+            scheduler,
             controller_tx,
+            dry_run: config.dry_run,
+            heartbeat: health::register(name, UPLOADER_STALL_AFTER),
         }
     }
 
     pub fn run(&self) {
         loop {
-            match self.controller_rx.recv() {
-                Err(err) => {
-                    info!("Channel disconnected, shutting down.");
-                    debug!("{}", err);
+            self.heartbeat.beat();
+            match self.scheduler.pop(health::HEARTBEAT_INTERVAL) {
+                Ok(None) => continue,
+                Err(Closed) => {
+                    info!("Scheduler closed, shutting down.");
                     break;
                 }
-                Ok(file) => {
-                    let upload_result = self.upload_file(&file);
+                Ok(Some(file)) => {
+                    let taken = Taken(&self.scheduler);
+                    let outcomes = self
+                        .targets_of(&file)
+                        .map(|target| {
+                            // Only the files going to a destination that can't be reached wait for it
+                            if target.breaker.is_open() {
+                                self.wait_for_s3(target, self.store(target, &file));
+                            }
+                            let started = Utc::now();
+                            let result = self.upload_file_when_reachable(&file, target);
+                            Outcome {
+                                uploader: self.name.clone(),
+                                destination: target.name.clone(),
+                                bucket: target.bucket(),
+                                started,
+                                finished: Utc::now(),
+                                result,
+                            }
+                        })
+                        .collect();
+                    // Before reporting, so that the controller sees room for more files
+                    drop(taken);
                     self.controller_tx
-                        .send((file, upload_result))
+                        .send((file, outcomes))
                         .unwrap_or_else(|err| warn!("Failed to send file to controller: {}", err));
                 }
             }
         }
     }
 
-    fn upload_file(&self, file: &File) -> Result<()> {
-        let upload_id = self.create_multipart_upload(&file)?;
-
-        self.upload_file_parts(&file, &upload_id)
-            .and_then(|multipart_upload| {
-                self.complete_multipart_upload(&file, multipart_upload, &upload_id)
-            })
-            .or_else(|err| {
-                self.abort_multipart_upload(&file, &upload_id);
-                Err(err)
-            })
+    /// The destinations of the file's directory, the default one if it has none,
+    /// apart from those the file already reached
+    fn targets_of<'a>(&'a self, file: &File) -> impl Iterator<Item = &'a Target> {
+        let default = [DEFAULT_DESTINATION.to_string()];
+        let names: Vec<_> = self
+            .rules
+            .destinations(&file.full_path)
+            .unwrap_or(&default)
+            .iter()
+            .filter(|name| !file.uploaded_to.contains(name))
+            .cloned()
+            .collect();
+        self.targets
+            .iter()
+            .filter(move |target| names.contains(&target.name))
     }
 
-    fn upload_file_parts(&self, file: &File, upload_id: &str) -> Result<CompletedMultipartUpload> {
-        let mut fs_file = FSFile::open(&file.full_path)?;
-        let mut part_number = 0;
-        let mut completed_parts: Vec<CompletedPart> = Vec::new();
-
+    /// Uploads the file, retrying it once the destination is reachable again if it was the cause of
+    /// the failure
+    ///
+    /// Files failing while the breaker is open aren't reported, so that they don't use up their attempts.
+    /// Other transient errors are retried a few times with a backoff.
+    fn upload_file_when_reachable(&self, file: &File, target: &Target) -> Result<Uploaded> {
+        let breaker = &target.breaker;
+        let mut attempt = 1;
         loop {
-            let mut buffer = vec![0; self.part_size];
-            part_number += 1;
-
-            match fs_file.read(&mut buffer) {
-                Ok(0) => break,
-                Ok(len) => {
-                    buffer.truncate(len);
-                    completed_parts.push(self.upload_part(
-                        buffer,
-                        file.key.to_str().unwrap().into(),
-                        part_number,
-                        upload_id,
-                    )?);
+            let result = self.upload_file(file, target);
+            let err = match &result {
+                Ok(_) => {
+                    breaker.success();
+                    return result;
                 }
-                Err(err) => {
-                    return Err(Error::Read(err));
+                Err(err) => err,
+            };
+            match err {
+                // Never a failure of the file, which waits until the destination answers
+                err if err.is_connectivity() => {
+                    if breaker.failure() {
+                        info!("Will retry {} once {} is reachable", file, target.name);
+                        self.wait_for_s3(target, self.store(target, file));
+                    } else {
+                        debug!("Retrying {} after a connectivity error: {}", file, err);
+                        thread::sleep(TRANSIENT_BACKOFF);
+                        self.heartbeat.beat();
+                    }
+                    continue;
                 }
+                // The store wasn't called
+                Error::Read(_) | Error::Encrypt(_) => (),
+                _ => breaker.success(),
+            }
+            if err.kind() == ErrorKind::Transient && attempt < TRANSIENT_ATTEMPTS {
+                let delay = TRANSIENT_BACKOFF * 2u32.pow(attempt - 1);
+                info!(
+                    "Retrying {} in {}s after a transient error: {}",
+                    file,
+                    delay.as_secs(),
+                    err
+                );
+                thread::sleep(delay);
+                self.heartbeat.beat();
+                attempt += 1;
+                continue;
             }
+            return result;
         }
+    }
 
-        Ok(CompletedMultipartUpload {
-            parts: Some(completed_parts),
-        })
+    /// Waits for the breaker of the destination to close, probing it when it's this uploader's turn
+    fn wait_for_s3(&self, target: &Target, store: &dyn ObjectStore) {
+        loop {
+            self.heartbeat.beat();
+            match target.breaker.wait(health::HEARTBEAT_INTERVAL) {
+                Wait::Closed => return,
+                Wait::Open => (),
+                Wait::Probe => {
+                    // Any answer, even an error, means the destination is reachable
+                    match store.head_object(PROBE_KEY) {
+                        Err(err) if err.connectivity => {
+                            debug!("{} is still unreachable: {}", target.name, err);
+                            target.breaker.probe_failed();
+                        }
+                        _ => target.breaker.success(),
+                    }
+                }
+            }
+        }
+    }
+
+    /// The store of the destination with the credentials of the file's directory
+    fn store<'a>(&self, target: &'a Target, file: &File) -> &'a dyn ObjectStore {
+        let credentials = self
+            .rules
+            .credentials(&file.full_path)
+            .unwrap_or(&self.credentials);
+        target
+            .stores
+            .iter()
+            .find(|(known, _)| known == credentials)
+            .map(|(_, store)| store.as_ref())
+            .expect("A store is built for all the credentials")
+    }
+
+    fn upload_file(&self, file: &File, target: &Target) -> Result<Uploaded> {
+        if self.dry_run {
+            info!(
+                "Would upload {} to {}/{}",
+                file, target.destination, file.key
+            );
+            return Ok(Uploaded::default());
+        }
+        let store = self.store(target, file);
+
+        let timer = metrics::UPLOAD_DURATION.start_timer();
+        let result = fs::metadata(&file.full_path)
+            .map_err(Error::Read)
+            .and_then(|metadata| {
+                let attributes = self.rules.attributes(file, &metadata);
+                if metadata.len() <= self.part_size as u64 {
+                    self.put_object(store, file, attributes)
+                } else {
+                    self.upload_multipart(store, file, metadata.len(), attributes)
+                }
+            });
+        if result.is_ok() {
+            timer.observe_duration();
+            metrics::LAST_UPLOAD.set(Utc::now().timestamp() as f64);
+        } else {
+            timer.stop_and_discard();
+        }
+        result
     }
 
-    fn upload_part(
+    /// Uploads a file small enough to fit in a single part
+    fn put_object(
         &self,
-        body: Vec<u8>,
-        key: &str,
-        part_number: i64,
-        upload_id: &str,
-    ) -> Result<CompletedPart> {
-        let content_length = body.len() as i64;
-        let digest = md5::compute(&body);
-        let content_md5 = base64::encode(digest.as_ref());
-        match self
-            .s3_client
-            .upload_part(UploadPartRequest {
-                part_number,
-                body: Some(body.into()),
-                content_length: Some(content_length),
-                content_md5: Some(content_md5),
-                bucket: self.bucket_name.to_owned(),
-                key: key.to_owned(),
-                upload_id: upload_id.to_owned(),
-                request_payer: self.request_payer.to_owned(),
-                ..Default::default()
-            })
-            .sync()
-        {
-            Ok(res) => {
-                let e_tag = res.e_tag.unwrap();
-                debug!("Uploaded part {} - etag: {}", part_number, e_tag);
-                Ok(CompletedPart {
-                    part_number: Some(part_number),
-                    e_tag: Some(e_tag),
-                })
+        store: &dyn ObjectStore,
+        file: &File,
+        mut attributes: ObjectAttributes,
+    ) -> Result<Uploaded> {
+        let plaintext = fs::read(&file.full_path)?;
+        self.heartbeat.beat();
+
+        let uploaded = Uploaded {
+            size: plaintext.len() as u64,
+            md5: format!("{:x}", md5::compute(&plaintext)),
+            ..Default::default()
+        };
+        let body = match self.object_cipher()? {
+            Some(cipher) => {
+                attributes.metadata.extend(cipher.metadata.clone());
+                cipher.encrypt_part(1, true, &plaintext)?
             }
-            Err(error) => Err(Error::UploadPart { part_number, error }),
-        }
+            None => plaintext,
+        };
+
+        let content_length = body.len() as u64;
+        let stored = store
+            .put_object(&file.key, body, &attributes)
+            .map_err(Error::PutObject)?;
+        metrics::BYTES_UPLOADED.inc_by(content_length);
+        debug!("Put object");
+        Ok(Uploaded {
+            e_tag: stored.e_tag,
+            version_id: stored.version_id,
+            ..uploaded
+        })
     }
 
-    fn complete_multipart_upload(
+    fn upload_multipart(
         &self,
+        store: &dyn ObjectStore,
         file: &File,
-        multipart_upload: CompletedMultipartUpload,
-        upload_id: &str,
-    ) -> Result<()> {
-        self.s3_client
-            .complete_multipart_upload(CompleteMultipartUploadRequest {
-                bucket: self.bucket_name.to_owned(),
-                key: file.key.to_str().unwrap().into(),
-                multipart_upload: Some(multipart_upload),
-                upload_id: upload_id.to_owned(),
-                request_payer: self.request_payer.to_owned(),
+        size: u64,
+        mut attributes: ObjectAttributes,
+    ) -> Result<Uploaded> {
+        let key = file.key.as_str();
+        let cipher = self.object_cipher()?;
+        if let Some(cipher) = &cipher {
+            attributes.metadata.extend(cipher.metadata.clone());
+        }
+        let upload_id = store
+            .create_multipart_upload(key, &attributes)
+            .map_err(Error::CreateMultipartUpload)?;
+
+        self.upload_file_parts(store, file, size, cipher.as_ref(), &upload_id)
+            .and_then(|(parts, mut uploaded)| {
+                let stored = store
+                    .complete_multipart_upload(key, &upload_id, parts)
+                    .map_err(Error::CompleteMultipartUpload)?;
+                debug!("Completed upload");
+                uploaded.e_tag = stored.e_tag;
+                uploaded.version_id = stored.version_id;
+                Ok(uploaded)
             })
-            .sync()?;
-        debug!("Completed upload");
-        Ok(())
-    }
-
-    fn create_multipart_upload(&self, file: &File) -> Result<String> {
-        match self
-            .s3_client
-            .create_multipart_upload(CreateMultipartUploadRequest {
-                bucket: self.bucket_name.clone(),
-                key: file.key.to_str().unwrap().into(),
-                ..Default::default()
+            .inspect_err(|_| match store.abort_multipart_upload(key, &upload_id) {
+                Ok(()) => warn!("Aborted upload of {} (upload id: {})", key, upload_id),
+                Err(err) => warn!(
+                    "Failed to abort upload of {} (upload id: {}): {}",
+                    key, upload_id, err
+                ),
             })
-            .sync()
-        {
-            Ok(result) => match result.upload_id {
-                Some(upload_id) => Ok(upload_id),
-                None => Err("Didn't get an upload_id".into()),
-            },
-            Err(err) => Err(Error::CreateMultipartUpload(err)),
-        }
     }
 
-    fn abort_multipart_upload(&self, file: &File, upload_id: &str) {
-        let key = file.key.to_str().unwrap().to_owned();
-        match self
-            .s3_client
-            .abort_multipart_upload(AbortMultipartUploadRequest {
-                bucket: self.bucket_name.to_owned(),
-                key: key.to_owned(),
-                upload_id: upload_id.into(),
-                request_payer: self.request_payer.to_owned(),
-            })
-            .sync()
-        {
-            Ok(_) => warn!("Aborted upload of {} (upload id: {})", key, upload_id),
-            Err(err) => warn!(
-                "Failed to abort upload of {} (upload id: {}): {}",
-                key, upload_id, err
-            ),
+    /// Draws a data key if client-side encryption is enabled
+    fn object_cipher(&self) -> Result<Option<ObjectCipher>> {
+        self.cse
+            .as_ref()
+            .map(|cse| cse.object_cipher(self.part_size))
+            .transpose()
+    }
+
+    /// Uploads the first `size` bytes of the file, hashing them along the way
+    ///
+    /// Parts are encrypted if a cipher is given, so their MD5 is the one of the ciphertext.
+    fn upload_file_parts(
+        &self,
+        store: &dyn ObjectStore,
+        file: &File,
+        size: u64,
+        cipher: Option<&ObjectCipher>,
+        upload_id: &str,
+    ) -> Result<(Vec<Part>, Uploaded)> {
+        let mut fs_file = FSFile::open(&file.full_path)?.take(size);
+        let mut part_number = 0;
+        let mut parts: Vec<Part> = Vec::new();
+        let mut read = 0;
+        let mut hash = md5::Context::new();
+
+        loop {
+            let mut buffer = Vec::with_capacity(self.part_size);
+            self.heartbeat.beat();
+
+            // Parts other than the last one must be full
+            let len = (&mut fs_file)
+                .take(self.part_size as u64)
+                .read_to_end(&mut buffer)?;
+            if len == 0 {
+                break;
+            }
+            part_number += 1;
+            read += len as u64;
+            hash.consume(&buffer);
+
+            let body = match cipher {
+                Some(cipher) => cipher.encrypt_part(part_number, read == size, &buffer)?,
+                None => buffer,
+            };
+            let content_length = body.len() as u64;
+            let e_tag = store
+                .upload_part(&file.key, upload_id, part_number, body)
+                .map_err(|error| Error::UploadPart { part_number, error })?;
+            metrics::BYTES_UPLOADED.inc_by(content_length);
+            debug!("Uploaded part {} - etag: {}", part_number, e_tag);
+            parts.push(Part {
+                number: part_number,
+                e_tag,
+            });
+        }
+
+        if read < size {
+            return Err(Error::Read(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "File was truncated during upload",
+            )));
         }
+
+        let uploaded = Uploaded {
+            size,
+            md5: format!("{:x}", hash.compute()),
+            ..Default::default()
+        };
+        Ok((parts, uploaded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Target, Uploader};
+    use crate::config::{Destination, SyncConfig};
+    use crate::controller::file::File;
+    use crate::scheduler::Scheduler;
+    use crate::uploader::breaker::CircuitBreaker;
+    use crate::uploader::error::Error;
+    use crate::uploader::store::directory::{DirectoryStore, MARKER};
+    use crate::uploader::store::memory::MemoryStore;
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_multipart_upload_to_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config =
+            SyncConfig::for_test(&["-w", dir.path().to_str().unwrap(), "-b", "bucket"]);
+        config.upload_part_size = 1;
+
+        let store = Arc::new(MemoryStore::default());
+        let (tx, _rx) = crossbeam_channel::unbounded();
+        let target = Target {
+            name: "default".into(),
+            destination: config.destinations[0].1.clone(),
+            stores: vec![(config.credentials.clone(), store.clone())],
+            breaker: Arc::new(CircuitBreaker::new("default")),
+        };
+        let uploader = Uploader::with_targets(
+            "uploader-test",
+            &config,
+            vec![target],
+            Arc::new(Scheduler::new(vec![], false)),
+            tx,
+        );
+        let target = &uploader.targets[0];
+
+        let data: Vec<u8> = (0..5 * 1024 * 1024 / 2)
+            .map(|num| (num % 251) as u8)
+            .collect();
+        let file = File {
+            full_path: dir.path().join("big.bin"),
+            key: "big.bin".into(),
+            uploaded_to: Vec::new(),
+        };
+        fs::write(&file.full_path, &data).unwrap();
+
+        let uploaded = uploader.upload_file(&file, target).unwrap();
+        assert_eq!(uploaded.md5, format!("{:x}", md5::compute(&data)));
+        assert_eq!(uploaded.e_tag.as_deref(), Some("\"memory-3\""));
+        assert_eq!(store.object("big.bin"), Some(data));
+
+        // A failed part aborts the upload, leaving the previous object in place
+        fs::write(&file.full_path, vec![0; 3 * 1024 * 1024]).unwrap();
+        store.fail_part(2);
+        let err = uploader.upload_file(&file, target).unwrap_err();
+        assert!(matches!(err, Error::UploadPart { part_number: 2, .. }));
+        assert_eq!(store.aborted(), vec!["big.bin"]);
+        assert_eq!(store.object("big.bin").unwrap().len(), 5 * 1024 * 1024 / 2);
+    }
+
+    #[test]
+    fn test_connectivity_errors_hold_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SyncConfig::for_test(&["-w", dir.path().to_str().unwrap(), "-b", "bucket"]);
+        let share = dir.path().join("share");
+        fs::create_dir(&share).unwrap();
+
+        let (tx, _rx) = crossbeam_channel::unbounded();
+        let target = Target {
+            name: "share".into(),
+            destination: Destination::Directory(share.clone()),
+            stores: vec![(
+                config.credentials.clone(),
+                Arc::new(DirectoryStore::new(share.clone())),
+            )],
+            breaker: Arc::new(CircuitBreaker::new("share")),
+        };
+        let uploader = Uploader::with_targets(
+            "uploader-test",
+            &config,
+            vec![target],
+            Arc::new(Scheduler::new(vec![], false)),
+            tx,
+        );
+        let file = File {
+            full_path: dir.path().join("a.txt"),
+            key: "a.txt".into(),
+            uploaded_to: Vec::new(),
+        };
+        fs::write(&file.full_path, b"abc").unwrap();
+
+        // The share is unavailable until the marker shows up, before the breaker trips
+        let marker = share.join(MARKER);
+        let mount = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(1500));
+            fs::write(marker, "").unwrap();
+        });
+        let uploaded = uploader.upload_file_when_reachable(&file, &uploader.targets[0]);
+        mount.join().unwrap();
+        assert!(uploaded.is_ok());
+        assert_eq!(fs::read(share.join("a.txt")).unwrap(), b"abc");
+        assert!(!uploader.targets[0].breaker.is_open());
     }
 }
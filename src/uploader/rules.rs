@@ -0,0 +1,248 @@
+//! Attributes given to the objects, configured per watched directory
+//!
+//! A file gets the rules of the deepest watched directory it is in.
+
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rusoto_s3::{CreateMultipartUploadRequest, PutObjectRequest};
+
+use crate::controller::file::File;
+use crate::template::{Template, Values, HOSTNAME};
+use crate::uploader::credentials::Credentials;
+
+pub const STORAGE_CLASSES: &[&str] = &[
+    "STANDARD",
+    "REDUCED_REDUNDANCY",
+    "STANDARD_IA",
+    "ONEZONE_IA",
+    "INTELLIGENT_TIERING",
+    "GLACIER",
+    "GLACIER_IR",
+    "DEEP_ARCHIVE",
+];
+
+/// Placeholders available in tags and metadata
+pub const PLACEHOLDERS: &[&str] = &["host", "mtime", "watch_dir"];
+
+/// S3 doesn't accept more tags per object
+pub const MAX_TAGS: usize = 10;
+
+#[derive(Clone, Debug, Default)]
+pub struct UploadRules {
+    pub storage_class: Option<String>,
+    /// Used as is, takes precedence over the guessed content type
+    pub content_type: Option<String>,
+    /// Guess the content type from the file extension
+    pub guess_content_type: bool,
+    pub cache_control: Option<String>,
+    pub tags: Vec<(String, Template)>,
+    /// Sent as `x-amz-meta-*` headers
+    pub metadata: Vec<(String, Template)>,
+    /// Used instead of the global credentials
+    pub credentials: Option<Credentials>,
+    /// Names of the destinations, the default one if empty
+    pub destinations: Vec<String>,
+}
+
+/// The rules of each watched directory
+#[derive(Clone, Debug, Default)]
+pub struct RuleSet {
+    rules: Vec<(PathBuf, UploadRules)>,
+}
+
+impl RuleSet {
+    /// Watched directories are canonicalized, like the watchers do, when they exist
+    pub fn new(rules: Vec<(PathBuf, UploadRules)>) -> Self {
+        let rules = rules
+            .into_iter()
+            .map(|(dir, rules)| (dir.canonicalize().unwrap_or(dir), rules))
+            .collect();
+        Self { rules }
+    }
+
+    fn find(&self, path: &Path) -> Option<&(PathBuf, UploadRules)> {
+        self.rules
+            .iter()
+            .filter(|(dir, _)| path.starts_with(dir))
+            .max_by_key(|(dir, _)| dir.components().count())
+    }
+
+    /// The credentials set for the directory of a file, if any
+    pub fn credentials(&self, path: &Path) -> Option<&Credentials> {
+        self.find(path)
+            .and_then(|(_, rules)| rules.credentials.as_ref())
+    }
+
+    /// The destinations set for the directory of a file, if any
+    pub fn destinations(&self, path: &Path) -> Option<&[String]> {
+        self.find(path)
+            .map(|(_, rules)| rules.destinations.as_slice())
+            .filter(|names| !names.is_empty())
+    }
+
+    /// All the credentials set for a directory
+    pub fn all_credentials(&self) -> impl Iterator<Item = &Credentials> {
+        self.rules
+            .iter()
+            .filter_map(|(_, rules)| rules.credentials.as_ref())
+    }
+
+    /// Computes the attributes of the object a file is uploaded to
+    pub fn attributes(&self, file: &File, metadata: &Metadata) -> ObjectAttributes {
+        let (dir, rules) = match self.find(&file.full_path) {
+            Some(found) => found,
+            None => return ObjectAttributes::default(),
+        };
+
+        let mut values = Values::new();
+        values.insert("host", HOSTNAME.clone());
+        if let Ok(modified) = metadata.modified() {
+            values.insert("mtime", DateTime::<Utc>::from(modified).to_rfc3339());
+        }
+        if let Some(name) = dir.file_name() {
+            values.insert("watch_dir", name.to_string_lossy().into());
+        }
+
+        let content_type = rules.content_type.clone().or_else(|| {
+            if rules.guess_content_type {
+                mime_guess::from_path(&file.full_path)
+                    .first()
+                    .map(|mime| mime.to_string())
+            } else {
+                None
+            }
+        });
+        let tagging = if rules.tags.is_empty() {
+            None
+        } else {
+            let tags: Vec<String> = rules
+                .tags
+                .iter()
+                .map(|(name, value)| {
+                    format!(
+                        "{}={}",
+                        utf8_percent_encode(name, NON_ALPHANUMERIC),
+                        utf8_percent_encode(&value.render(&values), NON_ALPHANUMERIC)
+                    )
+                })
+                .collect();
+            Some(tags.join("&"))
+        };
+
+        ObjectAttributes {
+            content_type,
+            storage_class: rules.storage_class.clone(),
+            cache_control: rules.cache_control.clone(),
+            tagging,
+            metadata: rules
+                .metadata
+                .iter()
+                .map(|(name, value)| (name.clone(), value.render(&values)))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ObjectAttributes {
+    pub content_type: Option<String>,
+    pub storage_class: Option<String>,
+    pub cache_control: Option<String>,
+    /// Tags, URL encoded
+    pub tagging: Option<String>,
+    pub metadata: HashMap<String, String>,
+}
+
+impl ObjectAttributes {
+    fn metadata(&self) -> Option<HashMap<String, String>> {
+        if self.metadata.is_empty() {
+            None
+        } else {
+            Some(self.metadata.clone())
+        }
+    }
+
+    pub fn apply_to_create(&self, request: &mut CreateMultipartUploadRequest) {
+        request.content_type = self.content_type.clone();
+        request.storage_class = self.storage_class.clone();
+        request.cache_control = self.cache_control.clone();
+        request.tagging = self.tagging.clone();
+        request.metadata = self.metadata();
+    }
+
+    pub fn apply_to_put(&self, request: &mut PutObjectRequest) {
+        request.content_type = self.content_type.clone();
+        request.storage_class = self.storage_class.clone();
+        request.cache_control = self.cache_control.clone();
+        request.tagging = self.tagging.clone();
+        request.metadata = self.metadata();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RuleSet, UploadRules, PLACEHOLDERS};
+    use crate::controller::file::{File, KeyTemplate};
+    use crate::template::Template;
+    use std::fs;
+
+    #[test]
+    fn test_deepest_watched_dir_rules_apply() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("reports");
+        fs::create_dir(&nested).unwrap();
+        let path = nested.join("q1.csv");
+        fs::write(&path, "a,b").unwrap();
+
+        let template = |source| Template::parse(source, PLACEHOLDERS).unwrap();
+        let rules = RuleSet::new(vec![
+            (
+                dir.path().into(),
+                UploadRules {
+                    storage_class: Some("GLACIER_IR".into()),
+                    ..Default::default()
+                },
+            ),
+            (
+                nested.clone(),
+                UploadRules {
+                    storage_class: Some("STANDARD_IA".into()),
+                    guess_content_type: true,
+                    tags: vec![
+                        ("team".into(), template("data & ops")),
+                        ("dir".into(), template("{watch_dir}")),
+                    ],
+                    metadata: vec![("source-host".into(), template("{host}"))],
+                    ..Default::default()
+                },
+            ),
+        ]);
+
+        let file = File::new(
+            &nested.canonicalize().unwrap(),
+            path.canonicalize().unwrap(),
+            &KeyTemplate::default(),
+        )
+        .unwrap();
+        let attributes = rules.attributes(&file, &fs::metadata(&path).unwrap());
+        assert_eq!(attributes.storage_class.as_deref(), Some("STANDARD_IA"));
+        assert_eq!(attributes.content_type.as_deref(), Some("text/csv"));
+        assert_eq!(
+            attributes.tagging.as_deref(),
+            Some("team=data%20%26%20ops&dir=reports")
+        );
+        assert!(!attributes.metadata["source-host"].is_empty());
+
+        let outside = File {
+            full_path: "/elsewhere/file".into(),
+            key: "elsewhere/file".into(),
+            uploaded_to: Vec::new(),
+        };
+        let attributes = rules.attributes(&outside, &fs::metadata(&path).unwrap());
+        assert_eq!(attributes.storage_class, None);
+    }
+}
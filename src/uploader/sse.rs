@@ -0,0 +1,155 @@
+//! Server-side encryption of the uploaded objects
+//!
+//! With SSE-C the key is sent along with every request writing data, parts included.
+
+use std::fs;
+use std::path::Path;
+
+use rusoto_s3::{
+    CreateMultipartUploadRequest, HeadObjectRequest, PutObjectRequest, UploadPartRequest,
+};
+
+const KEY_LEN: usize = 32;
+
+/// Reads a 256 bit key from a file, either as 32 raw bytes or base64 encoded
+pub fn read_key(path: &Path) -> Result<Vec<u8>, String> {
+    let content = fs::read(path).map_err(|err| err.to_string())?;
+    let key = if content.len() == KEY_LEN {
+        content
+    } else {
+        let text = String::from_utf8_lossy(&content);
+        base64::decode(text.trim()).map_err(|_| "Key must be raw or base64 encoded")?
+    };
+
+    if key.len() != KEY_LEN {
+        return Err(format!("Key must be {} bytes long", KEY_LEN));
+    }
+    Ok(key)
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ServerSideEncryption {
+    /// Whatever the bucket is configured with
+    #[default]
+    BucketDefault,
+    /// SSE-S3, with keys managed by S3
+    S3,
+    /// SSE-KMS, with the AWS managed key unless a key ID is given
+    Kms { key_id: Option<String> },
+    /// SSE-C, with a 256 bit key provided by us
+    Customer { key: Vec<u8> },
+}
+
+#[derive(Default)]
+struct Headers {
+    server_side_encryption: Option<String>,
+    ssekms_key_id: Option<String>,
+    sse_customer_algorithm: Option<String>,
+    sse_customer_key: Option<String>,
+    sse_customer_key_md5: Option<String>,
+}
+
+impl ServerSideEncryption {
+    /// Loads an SSE-C key
+    pub fn from_key_file(path: &Path) -> Result<Self, String> {
+        read_key(path).map(|key| Self::Customer { key })
+    }
+
+    fn headers(&self) -> Headers {
+        match self {
+            Self::BucketDefault => Headers::default(),
+            Self::S3 => Headers {
+                server_side_encryption: Some("AES256".into()),
+                ..Default::default()
+            },
+            Self::Kms { key_id } => Headers {
+                server_side_encryption: Some("aws:kms".into()),
+                ssekms_key_id: key_id.clone(),
+                ..Default::default()
+            },
+            Self::Customer { key } => Headers {
+                sse_customer_algorithm: Some("AES256".into()),
+                sse_customer_key: Some(base64::encode(key)),
+                sse_customer_key_md5: Some(base64::encode(md5::compute(key).as_ref())),
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn apply_to_create(&self, request: &mut CreateMultipartUploadRequest) {
+        let headers = self.headers();
+        request.server_side_encryption = headers.server_side_encryption;
+        request.ssekms_key_id = headers.ssekms_key_id;
+        request.sse_customer_algorithm = headers.sse_customer_algorithm;
+        request.sse_customer_key = headers.sse_customer_key;
+        request.sse_customer_key_md5 = headers.sse_customer_key_md5;
+    }
+
+    pub fn apply_to_put(&self, request: &mut PutObjectRequest) {
+        let headers = self.headers();
+        request.server_side_encryption = headers.server_side_encryption;
+        request.ssekms_key_id = headers.ssekms_key_id;
+        request.sse_customer_algorithm = headers.sse_customer_algorithm;
+        request.sse_customer_key = headers.sse_customer_key;
+        request.sse_customer_key_md5 = headers.sse_customer_key_md5;
+    }
+
+    /// Parts only need the SSE-C key, the other settings come from the multipart upload
+    pub fn apply_to_part(&self, request: &mut UploadPartRequest) {
+        let headers = self.headers();
+        request.sse_customer_algorithm = headers.sse_customer_algorithm;
+        request.sse_customer_key = headers.sse_customer_key;
+        request.sse_customer_key_md5 = headers.sse_customer_key_md5;
+    }
+
+    /// Objects encrypted with SSE-C can't even be looked at without the key
+    pub fn apply_to_head(&self, request: &mut HeadObjectRequest) {
+        let headers = self.headers();
+        request.sse_customer_algorithm = headers.sse_customer_algorithm;
+        request.sse_customer_key = headers.sse_customer_key;
+        request.sse_customer_key_md5 = headers.sse_customer_key_md5;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ServerSideEncryption;
+    use rusoto_s3::{CreateMultipartUploadRequest, UploadPartRequest};
+    use std::fs;
+
+    #[test]
+    fn test_customer_key_is_sent_with_parts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        fs::write(&path, format!("{}\n", base64::encode(&[7u8; 32]))).unwrap();
+        let sse = ServerSideEncryption::from_key_file(&path).unwrap();
+        assert_eq!(sse, ServerSideEncryption::Customer { key: vec![7; 32] });
+
+        let mut part = UploadPartRequest::default();
+        sse.apply_to_part(&mut part);
+        assert_eq!(part.sse_customer_algorithm.as_deref(), Some("AES256"));
+        assert_eq!(part.sse_customer_key, Some(base64::encode(&[7u8; 32])));
+        assert_eq!(
+            part.sse_customer_key_md5,
+            Some(base64::encode(md5::compute([7u8; 32]).as_ref()))
+        );
+
+        fs::write(&path, "too short").unwrap();
+        assert!(ServerSideEncryption::from_key_file(&path).is_err());
+    }
+
+    #[test]
+    fn test_kms_key_id_is_sent_on_create_only() {
+        let sse = ServerSideEncryption::Kms {
+            key_id: Some("alias/backups".into()),
+        };
+        let mut create = CreateMultipartUploadRequest::default();
+        sse.apply_to_create(&mut create);
+        assert_eq!(create.server_side_encryption.as_deref(), Some("aws:kms"));
+        assert_eq!(create.ssekms_key_id.as_deref(), Some("alias/backups"));
+
+        let mut part = UploadPartRequest::default();
+        sse.apply_to_part(&mut part);
+        assert_eq!(part.sse_customer_key, None);
+    }
+}
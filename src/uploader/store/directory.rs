@@ -0,0 +1,409 @@
+//! A directory receiving a copy of each file at its key, typically an NFS or SMB mount
+//!
+//! Objects are written to a hidden temporary file next to their final path, checked against the
+//! MD5 of what was sent, then renamed, so that readers of the directory never see partial files.
+//! Temporary files left behind by a crash or a share going away mid-upload aren't swept,
+//! as they can't be told apart from those of another instance writing to the same share.
+
+use std::collections::HashMap;
+use std::fs::{self, File as FSFile};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::uploader::error::{ErrorKind, StoreError};
+use crate::uploader::rules::ObjectAttributes;
+use crate::uploader::store::{ObjectStore, Part, StoreResult, Stored};
+
+/// Marks a destination directory that isn't a mount point of its own, such as a directory of a share
+///
+/// The destination only counts as available when it is a mount point or holds this file, as
+/// an unmounted share leaves an empty directory behind.
+pub const MARKER: &str = ".s3-file-sync";
+
+pub struct DirectoryStore {
+    root: PathBuf,
+    /// Ongoing multipart uploads, by upload ID
+    uploads: Mutex<HashMap<String, Upload>>,
+    /// Makes the names of the temporary files unique
+    count: AtomicU64,
+}
+
+struct Upload {
+    path: PathBuf,
+    temp_path: PathBuf,
+    temp_file: FSFile,
+    /// MD5 of the parts written so far, in order
+    sent: md5::Context,
+    e_tags: Vec<String>,
+}
+
+impl Upload {
+    /// Parts are appended, so they must come in order
+    fn write_part(&mut self, part_number: i64, body: &[u8]) -> StoreResult<String> {
+        if part_number != self.e_tags.len() as i64 + 1 {
+            return Err(StoreError::new(
+                ErrorKind::Permanent,
+                &format!("Part {} is out of order", part_number),
+            ));
+        }
+        self.temp_file.write_all(body).map_err(io_error)?;
+        self.sent.consume(body);
+        let e_tag = e_tag(md5::compute(body));
+        self.e_tags.push(e_tag.clone());
+        Ok(e_tag)
+    }
+
+    fn complete(&self, parts: &[Part]) -> StoreResult<Stored> {
+        let expected = parts.len() == self.e_tags.len()
+            && parts.iter().zip(1..).all(|(part, number)| {
+                part.number == number && part.e_tag == self.e_tags[number as usize - 1]
+            });
+        if !expected {
+            return Err(StoreError::new(
+                ErrorKind::Permanent,
+                "The parts don't match the uploaded ones",
+            ));
+        }
+        self.temp_file.sync_all().map_err(io_error)?;
+        let digest = self.sent.clone().compute();
+        verify(&self.temp_path, digest)?;
+        fs::rename(&self.temp_path, &self.path).map_err(io_error)?;
+        Ok(Stored {
+            e_tag: Some(e_tag(digest)),
+            version_id: None,
+        })
+    }
+}
+
+impl DirectoryStore {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            uploads: Mutex::new(HashMap::new()),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// Path of the object, which must stay under the root
+    fn path(&self, key: &str) -> StoreResult<PathBuf> {
+        let relative = Path::new(key);
+        let is_relative = relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if key.is_empty() || !is_relative {
+            return Err(StoreError::new(
+                ErrorKind::Permanent,
+                &format!("Key {} isn't a path under the destination directory", key),
+            ));
+        }
+        // Creating the parents of a file on a share that went away would fill the mount point instead
+        let mounted = is_mount_point(&self.root)
+            .map(|mounted| mounted || self.root.join(MARKER).exists())
+            .map_err(|err| err.to_string());
+        match mounted {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err(self.unavailable(&format!("not a mount point and no {} marker", MARKER)))
+            }
+            Err(err) => return Err(self.unavailable(&err)),
+        }
+        Ok(self.root.join(relative))
+    }
+
+    fn unavailable(&self, reason: &str) -> StoreError {
+        StoreError {
+            kind: ErrorKind::Transient,
+            connectivity: true,
+            message: format!("{} is unavailable: {}", self.root.display(), reason),
+        }
+    }
+
+    /// Creates the parents of the object and returns the path of its temporary file
+    fn temp_path(&self, path: &Path) -> StoreResult<PathBuf> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(io_error)?;
+        }
+        let count = self.count.fetch_add(1, Ordering::Relaxed);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        Ok(path.with_file_name(format!(".{}.{}-{}.tmp", name, process::id(), count)))
+    }
+
+    fn take(&self, upload_id: &str) -> StoreResult<Upload> {
+        self.uploads
+            .lock()
+            .unwrap()
+            .remove(upload_id)
+            .ok_or_else(|| StoreError::new(ErrorKind::Permanent, "No such upload"))
+    }
+
+    fn put_back(&self, upload_id: &str, upload: Upload) {
+        self.uploads
+            .lock()
+            .unwrap()
+            .insert(upload_id.into(), upload);
+    }
+}
+
+impl ObjectStore for DirectoryStore {
+    fn put_object(&self, key: &str, body: Vec<u8>, _: &ObjectAttributes) -> StoreResult<Stored> {
+        let path = self.path(key)?;
+        let temp_path = self.temp_path(&path)?;
+        let digest = md5::compute(&body);
+        let result = write_file(&temp_path, &body)
+            .map_err(io_error)
+            .and_then(|_| verify(&temp_path, digest))
+            .and_then(|_| fs::rename(&temp_path, &path).map_err(io_error));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result.map(|_| Stored {
+            e_tag: Some(e_tag(digest)),
+            version_id: None,
+        })
+    }
+
+    fn create_multipart_upload(&self, key: &str, _: &ObjectAttributes) -> StoreResult<String> {
+        let path = self.path(key)?;
+        let temp_path = self.temp_path(&path)?;
+        let temp_file = FSFile::create(&temp_path).map_err(io_error)?;
+        let upload_id = temp_path.to_string_lossy().into_owned();
+        let upload = Upload {
+            path,
+            temp_path,
+            temp_file,
+            sent: md5::Context::new(),
+            e_tags: Vec::new(),
+        };
+        self.put_back(&upload_id, upload);
+        Ok(upload_id)
+    }
+
+    fn upload_part(
+        &self,
+        _: &str,
+        upload_id: &str,
+        part_number: i64,
+        body: Vec<u8>,
+    ) -> StoreResult<String> {
+        // Out of the map while writing, so that other uploads aren't held up
+        let mut upload = self.take(upload_id)?;
+        let result = upload.write_part(part_number, &body);
+        self.put_back(upload_id, upload);
+        result
+    }
+
+    fn complete_multipart_upload(
+        &self,
+        _: &str,
+        upload_id: &str,
+        parts: Vec<Part>,
+    ) -> StoreResult<Stored> {
+        let upload = self.take(upload_id)?;
+        let result = upload.complete(&parts);
+        // Left for the abort
+        if result.is_err() {
+            self.put_back(upload_id, upload);
+        }
+        result
+    }
+
+    fn abort_multipart_upload(&self, _: &str, upload_id: &str) -> StoreResult<()> {
+        let upload = self.take(upload_id)?;
+        drop(upload.temp_file);
+        fs::remove_file(&upload.temp_path).map_err(io_error)
+    }
+
+    fn head_object(&self, key: &str) -> StoreResult<Option<u64>> {
+        match fs::metadata(self.path(key)?) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(io_error(err)),
+        }
+    }
+}
+
+fn e_tag(digest: md5::Digest) -> String {
+    format!("\"{:x}\"", digest)
+}
+
+fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = FSFile::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+/// Reads the file back, to catch what got lost on the way to the share
+fn verify(path: &Path, expected: md5::Digest) -> StoreResult<()> {
+    let mut file = FSFile::open(path).map_err(io_error)?;
+    let mut hash = md5::Context::new();
+    let mut buffer = vec![0; 1024 * 1024];
+    loop {
+        match file.read(&mut buffer).map_err(io_error)? {
+            0 => break,
+            len => hash.consume(&buffer[..len]),
+        }
+    }
+    if hash.compute() == expected {
+        Ok(())
+    } else {
+        Err(StoreError::new(
+            ErrorKind::Transient,
+            &format!(
+                "The checksum of {} doesn't match the data sent",
+                path.display()
+            ),
+        ))
+    }
+}
+
+/// A share that went away is like S3 being unreachable, other errors are classified like S3 ones
+fn io_error(err: io::Error) -> StoreError {
+    let connectivity = is_connectivity_error(&err);
+    let kind = match err.kind() {
+        _ if connectivity => ErrorKind::Transient,
+        io::ErrorKind::PermissionDenied
+        | io::ErrorKind::ReadOnlyFilesystem
+        | io::ErrorKind::InvalidFilename => ErrorKind::Permanent,
+        _ => ErrorKind::Transient,
+    };
+    StoreError {
+        kind,
+        connectivity,
+        message: err.to_string(),
+    }
+}
+
+#[cfg(unix)]
+fn is_connectivity_error(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::ESTALE)
+            | Some(libc::ENOTCONN)
+            | Some(libc::ETIMEDOUT)
+            | Some(libc::EHOSTDOWN)
+            | Some(libc::EHOSTUNREACH)
+            | Some(libc::ECONNRESET)
+            | Some(libc::ECONNABORTED)
+    )
+}
+
+/// The OS codes differ, the kinds std maps them to are close enough
+#[cfg(not(unix))]
+fn is_connectivity_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::StaleNetworkFileHandle
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// Whether the directory is the root of a file system, such as a mounted share
+#[cfg(unix)]
+fn is_mount_point(path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(fs::metadata(path)?.dev() != fs::metadata(path.join(".."))?.dev())
+}
+
+#[cfg(not(unix))]
+fn is_mount_point(path: &Path) -> io::Result<bool> {
+    fs::metadata(path).map(|_| false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DirectoryStore, MARKER};
+    use crate::uploader::error::ErrorKind;
+    use crate::uploader::rules::ObjectAttributes;
+    use crate::uploader::store::{ObjectStore, Part};
+    use std::fs;
+
+    #[test]
+    fn test_directory_store() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(MARKER), "").unwrap();
+        let store = DirectoryStore::new(dir.path().into());
+        let attributes = ObjectAttributes::default();
+
+        store
+            .put_object("a/b.txt", b"abc".to_vec(), &attributes)
+            .unwrap();
+        assert_eq!(fs::read(dir.path().join("a/b.txt")).unwrap(), b"abc");
+        assert_eq!(store.head_object("a/b.txt").unwrap(), Some(3));
+        assert_eq!(store.head_object("a/c.txt").unwrap(), None);
+
+        let upload_id = store
+            .create_multipart_upload("a/c.txt", &attributes)
+            .unwrap();
+        let parts: Vec<Part> = [&b"abc"[..], b"def"]
+            .iter()
+            .zip(1..)
+            .map(|(body, number)| Part {
+                number,
+                e_tag: store
+                    .upload_part("a/c.txt", &upload_id, number, body.to_vec())
+                    .unwrap(),
+            })
+            .collect();
+        // Not visible before the upload is completed
+        assert_eq!(store.head_object("a/c.txt").unwrap(), None);
+        let stored = store
+            .complete_multipart_upload("a/c.txt", &upload_id, parts)
+            .unwrap();
+        assert_eq!(
+            stored.e_tag,
+            Some(format!("\"{:x}\"", md5::compute(b"abcdef")))
+        );
+        assert_eq!(fs::read(dir.path().join("a/c.txt")).unwrap(), b"abcdef");
+
+        let upload_id = store
+            .create_multipart_upload("a/d.txt", &attributes)
+            .unwrap();
+        let err = store
+            .upload_part("a/d.txt", &upload_id, 2, vec![0])
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Permanent);
+        store.abort_multipart_upload("a/d.txt", &upload_id).unwrap();
+        // Only the objects are left
+        let mut names: Vec<_> = fs::read_dir(dir.path().join("a"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["b.txt", "c.txt"]);
+
+        for key in &["../escape", "/etc/passwd", ""] {
+            let err = store.put_object(key, vec![], &attributes).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::Permanent);
+        }
+    }
+
+    #[test]
+    fn test_unmounted_directory_is_unavailable() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DirectoryStore::new(dir.path().into());
+        let attributes = ObjectAttributes::default();
+
+        // A temporary directory is no mount point, as an unmounted share
+        let err = store
+            .put_object("a.txt", b"abc".to_vec(), &attributes)
+            .unwrap_err();
+        assert!(err.connectivity);
+        assert_eq!(err.kind, ErrorKind::Transient);
+        assert!(!dir.path().join("a.txt").exists());
+
+        fs::write(dir.path().join(MARKER), "").unwrap();
+        assert!(store
+            .put_object("a.txt", b"abc".to_vec(), &attributes)
+            .is_ok());
+
+        fs::remove_dir_all(dir.path()).unwrap();
+        assert!(store.head_object("a.txt").unwrap_err().connectivity);
+    }
+}
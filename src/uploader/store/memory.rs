@@ -0,0 +1,121 @@
+//! Keeps the objects in memory, to test the uploaders without a server
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use crate::uploader::error::{ErrorKind, StoreError};
+use crate::uploader::rules::ObjectAttributes;
+use crate::uploader::store::{ObjectStore, Part, StoreResult, Stored};
+
+#[derive(Default)]
+struct State {
+    objects: HashMap<String, Vec<u8>>,
+    /// Parts of the ongoing uploads, by upload ID
+    uploads: HashMap<String, BTreeMap<i64, Vec<u8>>>,
+    upload_count: u64,
+    aborted: Vec<String>,
+    /// The next upload of this part number fails
+    fail_part: Option<i64>,
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<State>,
+}
+
+impl MemoryStore {
+    pub fn object(&self, key: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().objects.get(key).cloned()
+    }
+
+    /// Keys of the aborted uploads
+    pub fn aborted(&self) -> Vec<String> {
+        self.state.lock().unwrap().aborted.clone()
+    }
+
+    pub fn fail_part(&self, part_number: i64) {
+        self.state.lock().unwrap().fail_part = Some(part_number);
+    }
+}
+
+fn e_tag(data: &[u8]) -> String {
+    format!("\"{:x}\"", md5::compute(data))
+}
+
+fn no_such_upload() -> StoreError {
+    StoreError::new(ErrorKind::Permanent, "No such upload")
+}
+
+impl ObjectStore for MemoryStore {
+    fn put_object(&self, key: &str, body: Vec<u8>, _: &ObjectAttributes) -> StoreResult<Stored> {
+        let e_tag = e_tag(&body);
+        self.state.lock().unwrap().objects.insert(key.into(), body);
+        Ok(Stored {
+            e_tag: Some(e_tag),
+            version_id: None,
+        })
+    }
+
+    fn create_multipart_upload(&self, _: &str, _: &ObjectAttributes) -> StoreResult<String> {
+        let mut state = self.state.lock().unwrap();
+        state.upload_count += 1;
+        let upload_id = format!("upload-{}", state.upload_count);
+        state.uploads.insert(upload_id.clone(), BTreeMap::new());
+        Ok(upload_id)
+    }
+
+    fn upload_part(
+        &self,
+        _: &str,
+        upload_id: &str,
+        part_number: i64,
+        body: Vec<u8>,
+    ) -> StoreResult<String> {
+        let mut state = self.state.lock().unwrap();
+        if state.fail_part == Some(part_number) {
+            state.fail_part = None;
+            return Err(StoreError::new(ErrorKind::Permanent, "Injected failure"));
+        }
+        let e_tag = e_tag(&body);
+        let parts = state
+            .uploads
+            .get_mut(upload_id)
+            .ok_or_else(no_such_upload)?;
+        parts.insert(part_number, body);
+        Ok(e_tag)
+    }
+
+    fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<Part>,
+    ) -> StoreResult<Stored> {
+        let mut state = self.state.lock().unwrap();
+        let mut uploaded = state.uploads.remove(upload_id).ok_or_else(no_such_upload)?;
+        let mut data = Vec::new();
+        for part in &parts {
+            match uploaded.remove(&part.number) {
+                Some(body) if e_tag(&body) == part.e_tag => data.extend(body),
+                _ => return Err(StoreError::new(ErrorKind::Permanent, "Invalid part")),
+            }
+        }
+        state.objects.insert(key.into(), data);
+        Ok(Stored {
+            e_tag: Some(format!("\"memory-{}\"", parts.len())),
+            version_id: None,
+        })
+    }
+
+    fn abort_multipart_upload(&self, key: &str, upload_id: &str) -> StoreResult<()> {
+        let mut state = self.state.lock().unwrap();
+        state.uploads.remove(upload_id).ok_or_else(no_such_upload)?;
+        state.aborted.push(key.into());
+        Ok(())
+    }
+
+    fn head_object(&self, key: &str) -> StoreResult<Option<u64>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.objects.get(key).map(|data| data.len() as u64))
+    }
+}
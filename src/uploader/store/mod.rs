@@ -0,0 +1,69 @@
+//! Where uploaded files end up
+//!
+//! The uploaders read, hash, encrypt and split the files, and hand the resulting requests to an
+//! `ObjectStore`. Multipart uploads follow the S3 model: parts are numbered from 1 and the object
+//! only appears once the upload is completed.
+
+use crate::uploader::error::StoreError;
+use crate::uploader::rules::ObjectAttributes;
+
+pub mod directory;
+#[cfg(test)]
+pub mod memory;
+pub mod s3;
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// A stored object
+#[derive(Debug, Default)]
+pub struct Stored {
+    pub e_tag: Option<String>,
+    pub version_id: Option<String>,
+}
+
+/// An uploaded part of a multipart upload
+#[derive(Debug)]
+pub struct Part {
+    pub number: i64,
+    pub e_tag: String,
+}
+
+pub trait ObjectStore: Send + Sync {
+    /// Stores an object in a single request
+    fn put_object(
+        &self,
+        key: &str,
+        body: Vec<u8>,
+        attributes: &ObjectAttributes,
+    ) -> StoreResult<Stored>;
+
+    /// Returns the ID of the new upload
+    fn create_multipart_upload(
+        &self,
+        key: &str,
+        attributes: &ObjectAttributes,
+    ) -> StoreResult<String>;
+
+    /// Returns the ETag of the part
+    fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i64,
+        body: Vec<u8>,
+    ) -> StoreResult<String>;
+
+    /// Assembles the parts, in order, into the object
+    fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<Part>,
+    ) -> StoreResult<Stored>;
+
+    /// Discards the parts uploaded so far
+    fn abort_multipart_upload(&self, key: &str, upload_id: &str) -> StoreResult<()>;
+
+    /// Returns the size of the object, or `None` if there is no such object
+    fn head_object(&self, key: &str) -> StoreResult<Option<u64>>;
+}
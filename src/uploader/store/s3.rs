@@ -0,0 +1,268 @@
+//! The S3 bucket of the configuration, or any S3-compatible service given with `--endpoint-url`
+
+use std::error::Error as StdError;
+use std::sync::Arc;
+
+use rusoto_core::request::BufferedHttpResponse;
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateMultipartUploadRequest, HeadObjectError, HeadObjectRequest,
+    PutObjectRequest, S3Client, StreamingBody, UploadPartRequest, S3,
+};
+
+use crate::config::SyncConfig;
+use crate::throttle::Throttle;
+use crate::uploader::error::{ErrorKind, StoreError};
+use crate::uploader::rules::ObjectAttributes;
+use crate::uploader::sse::ServerSideEncryption;
+use crate::uploader::store::{ObjectStore, Part, StoreResult, Stored};
+
+pub struct S3Store {
+    client: S3Client,
+    bucket_name: String,
+    request_payer: Option<String>,
+    throttle: Option<Arc<Throttle>>,
+    sse: ServerSideEncryption,
+}
+
+impl S3Store {
+    pub fn new(client: S3Client, bucket_name: &str, config: &SyncConfig) -> Self {
+        Self {
+            client,
+            bucket_name: bucket_name.into(),
+            request_payer: None,
+            throttle: config.throttle.clone(),
+            sse: config.sse.clone(),
+        }
+    }
+
+    /// Request bodies are sent no faster than the bandwidth limit
+    fn body(&self, data: Vec<u8>) -> StreamingBody {
+        match &self.throttle {
+            Some(throttle) => throttle.body(data),
+            None => data.into(),
+        }
+    }
+}
+
+impl ObjectStore for S3Store {
+    fn put_object(
+        &self,
+        key: &str,
+        body: Vec<u8>,
+        attributes: &ObjectAttributes,
+    ) -> StoreResult<Stored> {
+        let content_length = body.len() as i64;
+        let digest = md5::compute(&body);
+        let mut request = PutObjectRequest {
+            bucket: self.bucket_name.to_owned(),
+            key: key.to_owned(),
+            content_length: Some(content_length),
+            content_md5: Some(base64::encode(digest.as_ref())),
+            body: Some(self.body(body)),
+            request_payer: self.request_payer.to_owned(),
+            ..Default::default()
+        };
+        attributes.apply_to_put(&mut request);
+        self.sse.apply_to_put(&mut request);
+
+        let output = self.client.put_object(request).sync()?;
+        Ok(Stored {
+            e_tag: output.e_tag,
+            version_id: output.version_id,
+        })
+    }
+
+    fn create_multipart_upload(
+        &self,
+        key: &str,
+        attributes: &ObjectAttributes,
+    ) -> StoreResult<String> {
+        let mut request = CreateMultipartUploadRequest {
+            bucket: self.bucket_name.clone(),
+            key: key.to_owned(),
+            ..Default::default()
+        };
+        attributes.apply_to_create(&mut request);
+        self.sse.apply_to_create(&mut request);
+
+        let output = self.client.create_multipart_upload(request).sync()?;
+        output
+            .upload_id
+            .ok_or_else(|| StoreError::new(ErrorKind::Transient, "Didn't get an upload_id"))
+    }
+
+    fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i64,
+        body: Vec<u8>,
+    ) -> StoreResult<String> {
+        let content_length = body.len() as i64;
+        let digest = md5::compute(&body);
+        let content_md5 = base64::encode(digest.as_ref());
+        let mut request = UploadPartRequest {
+            part_number,
+            body: Some(self.body(body)),
+            content_length: Some(content_length),
+            content_md5: Some(content_md5),
+            bucket: self.bucket_name.to_owned(),
+            key: key.to_owned(),
+            upload_id: upload_id.to_owned(),
+            request_payer: self.request_payer.to_owned(),
+            ..Default::default()
+        };
+        self.sse.apply_to_part(&mut request);
+
+        let output = self.client.upload_part(request).sync()?;
+        output
+            .e_tag
+            .ok_or_else(|| StoreError::new(ErrorKind::Transient, "Didn't get an ETag for the part"))
+    }
+
+    fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<Part>,
+    ) -> StoreResult<Stored> {
+        let parts = parts
+            .into_iter()
+            .map(|part| CompletedPart {
+                part_number: Some(part.number),
+                e_tag: Some(part.e_tag),
+            })
+            .collect();
+        let output = self
+            .client
+            .complete_multipart_upload(CompleteMultipartUploadRequest {
+                bucket: self.bucket_name.to_owned(),
+                key: key.to_owned(),
+                multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+                upload_id: upload_id.to_owned(),
+                request_payer: self.request_payer.to_owned(),
+            })
+            .sync()?;
+        Ok(Stored {
+            e_tag: output.e_tag,
+            version_id: output.version_id,
+        })
+    }
+
+    fn abort_multipart_upload(&self, key: &str, upload_id: &str) -> StoreResult<()> {
+        self.client
+            .abort_multipart_upload(AbortMultipartUploadRequest {
+                bucket: self.bucket_name.to_owned(),
+                key: key.to_owned(),
+                upload_id: upload_id.into(),
+                request_payer: self.request_payer.to_owned(),
+            })
+            .sync()?;
+        Ok(())
+    }
+
+    fn head_object(&self, key: &str) -> StoreResult<Option<u64>> {
+        let mut request = HeadObjectRequest {
+            bucket: self.bucket_name.to_owned(),
+            key: key.to_owned(),
+            request_payer: self.request_payer.to_owned(),
+            ..Default::default()
+        };
+        self.sse.apply_to_head(&mut request);
+
+        match self.client.head_object(request).sync() {
+            Ok(output) => Ok(Some(output.content_length.unwrap_or_default() as u64)),
+            Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(None),
+            // HEAD responses have no body to tell the error code
+            Err(RusotoError::Unknown(response)) if response.status.as_u16() == 404 => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl<E: StdError + 'static> From<RusotoError<E>> for StoreError {
+    fn from(err: RusotoError<E>) -> Self {
+        Self {
+            kind: rusoto_kind(&err),
+            // The request couldn't be sent or its response couldn't be read, which includes timeouts
+            connectivity: matches!(err, RusotoError::HttpDispatch(_)),
+            message: err.to_string(),
+        }
+    }
+}
+
+fn rusoto_kind<E>(err: &RusotoError<E>) -> ErrorKind {
+    match err {
+        RusotoError::HttpDispatch(_) | RusotoError::ParseError(_) => ErrorKind::Transient,
+        RusotoError::Credentials(_) => ErrorKind::Auth,
+        RusotoError::Validation(_) | RusotoError::Service(_) => ErrorKind::Permanent,
+        // S3 errors all end up here, as rusoto doesn't know the codes of these operations
+        RusotoError::Unknown(response) => response_kind(response),
+    }
+}
+
+/// Classifies an S3 error response by its code, or by its status if it has no body, like HEAD responses
+fn response_kind(response: &BufferedHttpResponse) -> ErrorKind {
+    let body = String::from_utf8_lossy(&response.body);
+    match error_code(&body) {
+        Some("ExpiredToken")
+        | Some("TokenRefreshRequired")
+        | Some("InvalidAccessKeyId")
+        | Some("InvalidToken")
+        | Some("SignatureDoesNotMatch")
+        | Some("RequestTimeTooSkewed") => ErrorKind::Auth,
+        Some("SlowDown")
+        | Some("RequestTimeout")
+        | Some("InternalError")
+        | Some("ServiceUnavailable")
+        | Some("OperationAborted") => ErrorKind::Transient,
+        _ => match response.status.as_u16() {
+            408 | 429 | 500..=599 => ErrorKind::Transient,
+            _ => ErrorKind::Permanent,
+        },
+    }
+}
+
+/// Finds the code of an S3 error response
+///
+/// Error responses have a single `Code` element, whose value never needs escaping.
+fn error_code(body: &str) -> Option<&str> {
+    let start = body.find("<Code>")? + "<Code>".len();
+    let end = start + body[start..].find("</Code>")?;
+    Some(body[start..end].trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::uploader::error::{ErrorKind, StoreError};
+    use http::StatusCode;
+    use rusoto_core::request::BufferedHttpResponse;
+    use rusoto_core::RusotoError;
+    use rusoto_s3::PutObjectError;
+
+    fn s3_error(status: u16, code: &str) -> StoreError {
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Error><Code>{}</Code><Message>...</Message></Error>",
+            code
+        );
+        RusotoError::<PutObjectError>::Unknown(BufferedHttpResponse {
+            status: StatusCode::from_u16(status).unwrap(),
+            body: body.into(),
+            headers: Default::default(),
+        })
+        .into()
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        assert_eq!(s3_error(503, "SlowDown").kind, ErrorKind::Transient);
+        assert_eq!(s3_error(400, "RequestTimeout").kind, ErrorKind::Transient);
+        assert_eq!(s3_error(400, "ExpiredToken").kind, ErrorKind::Auth);
+        assert_eq!(s3_error(403, "AccessDenied").kind, ErrorKind::Permanent);
+        assert_eq!(s3_error(404, "NoSuchBucket").kind, ErrorKind::Permanent);
+        assert_eq!(s3_error(502, "").kind, ErrorKind::Transient);
+        assert!(!s3_error(503, "SlowDown").connectivity);
+    }
+}
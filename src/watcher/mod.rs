@@ -1,6 +1,7 @@
 use std::collections::HashSet;
+use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{channel, Receiver};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
 use std::time::Duration;
 
 use crossbeam_channel::Sender;
@@ -9,42 +10,43 @@ use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
 
 pub mod error;
 
-use crate::controller::file::File;
+use crate::controller::file::{File, KeyTemplate};
+use crate::health::{self, Heartbeat};
+use crate::metrics;
 use crate::watcher::error::{Error, Result};
 
+/// The watcher beats between events, which never take long to handle
+const WATCHER_STALL_AFTER: Duration = Duration::from_secs(60);
+
 /// Watches a directory and sends events for created files
 ///
 /// Only one directory tree is watched.
 /// This allows to upload files from each tree to its own directory.
 pub struct FileWatcher {
     pub base_path: PathBuf,
+    key_template: KeyTemplate,
     controller_tx: Sender<File>,
     watcher_rx: Receiver<DebouncedEvent>,
     _watcher: RecommendedWatcher,
+    heartbeat: Heartbeat,
 }
 
 impl FileWatcher {
     pub fn create_watchers<P: AsRef<Path>>(
         paths: &[P],
+        key_template: &KeyTemplate,
         controller_tx: Sender<File>,
         watcher_duration: u64,
     ) -> Result<Vec<FileWatcher>> {
-        let mut canonical_paths = Vec::new();
-
-        for path in paths {
-            canonical_paths.push(
-                path.as_ref()
-                    .canonicalize()
-                    .or_else(|err| Err(Error::not_canon(path, err)))?,
-            );
-        }
-
-        let filtered_paths = get_paths(&canonical_paths);
-
         let mut watchers = Vec::new();
 
-        for path in filtered_paths {
-            watchers.push(Self::new(&path, watcher_duration, controller_tx.clone())?)
+        for path in tree_roots(paths)? {
+            watchers.push(Self::new(
+                &path,
+                key_template.clone(),
+                watcher_duration,
+                controller_tx.clone(),
+            )?)
         }
 
         Ok(watchers)
@@ -52,6 +54,7 @@ impl FileWatcher {
 
     pub fn new<P: AsRef<Path>>(
         path: &P,
+        key_template: KeyTemplate,
         delay: u64,
         controller_tx: Sender<File>,
     ) -> Result<FileWatcher> {
@@ -67,21 +70,35 @@ impl FileWatcher {
 
         _watcher.watch(&base_path, RecursiveMode::Recursive)?;
 
+        let heartbeat = health::register(&base_path.display().to_string(), WATCHER_STALL_AFTER);
+
         Ok(FileWatcher {
             base_path,
+            key_template,
             controller_tx,
             watcher_rx,
             _watcher,
+            heartbeat,
         })
     }
 
     pub fn run(&self) {
         info!("Started watcher");
-        for msg in self.watcher_rx.iter() {
+        loop {
+            self.heartbeat.beat();
+            let msg = match self.watcher_rx.recv_timeout(health::HEARTBEAT_INTERVAL) {
+                Ok(msg) => msg,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
             match msg {
                 DebouncedEvent::Create(path) => {
                     self.handle_event(path);
                 }
+                DebouncedEvent::Remove(path) => {
+                    debug!("Removed: {}", path.display());
+                    metrics::FILES_DELETED.inc();
+                }
                 DebouncedEvent::Error(err, path) => {
                     warn!("Error watching files:[{:?}] {:?}", path, err)
                 }
@@ -100,22 +117,69 @@ impl FileWatcher {
             return;
         }
 
-        match path.strip_prefix(&self.base_path.parent().unwrap()) {
-            Ok(stripped_path) => {
-                debug!("Detected file: {}", stripped_path.display());
-                let file = File {
-                    full_path: path.to_owned(),
-                    key: stripped_path.into(),
-                };
+        let path_display = path.display().to_string();
+        match File::new(&self.base_path, path, &self.key_template) {
+            Ok(file) => {
+                debug!("Detected file: {}", file.key);
+                metrics::FILES_DETECTED.inc();
                 self.controller_tx.send(file).unwrap_or_else(|err| {
                     warn!("Failed to notify file detection: {}", err);
                 });
             }
-            Err(err) => warn!("Failed to remove base path: {}", err),
+            Err(err) => warn!("Ignoring {}: {}", path_display, err),
         }
     }
 }
 
+/// Canonicalizes the provided paths and keeps the roots of the trees they form
+pub fn tree_roots<P: AsRef<Path>>(paths: &[P]) -> Result<Vec<PathBuf>> {
+    let mut canonical_paths = Vec::new();
+
+    for path in paths {
+        canonical_paths.push(
+            path.as_ref()
+                .canonicalize()
+                .map_err(|err| Error::not_canon(path, err))?,
+        );
+    }
+
+    Ok(get_paths(&canonical_paths)
+        .into_iter()
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Lists the files in the tree rooted at `base_path`
+///
+/// This is used when there's no watcher to report files as they're created.
+pub fn scan_tree(base_path: &Path, key_template: &KeyTemplate) -> Result<Vec<File>> {
+    if !base_path.is_dir() {
+        return Err(Error::not_dir(base_path));
+    }
+
+    let mut files = Vec::new();
+    let mut dirs = vec![base_path.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            // Symlinked directories aren't followed, they could create loops
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else if path.is_file() {
+                let path_display = path.display().to_string();
+                match File::new(base_path, path, key_template) {
+                    Ok(file) => files.push(file),
+                    Err(err) => warn!("Ignoring {}: {}", path_display, err),
+                }
+            }
+        }
+    }
+
+    Ok(files)
+}
+
 /// Gets the tree roots of the provided paths
 ///
 /// If any one of the trees is a subtree of another, it is ignored. Ex:
@@ -139,6 +203,7 @@ fn get_paths<P: AsRef<Path>>(paths: &[P]) -> HashSet<&Path> {
 #[cfg(test)]
 mod tests {
     use super::FileWatcher;
+    use crate::controller::file::KeyTemplate;
     use crossbeam_channel::unbounded;
     use std::path::Path;
 
@@ -147,7 +212,29 @@ mod tests {
         let paths = [Path::new("/some/missing/path/")];
         let (watcher_tx, _) = unbounded();
 
-        assert!(FileWatcher::create_watchers(&paths, watcher_tx, 2).is_err());
+        assert!(
+            FileWatcher::create_watchers(&paths, &KeyTemplate::default(), watcher_tx, 2).is_err()
+        );
+    }
+
+    #[test]
+    fn test_scan_tree_finds_nested_files() {
+        use super::scan_tree;
+        use std::fs;
+
+        let dir = tempfile::tempdir().unwrap();
+        let base_path = dir.path().join("base");
+        fs::create_dir_all(base_path.join("a/b")).unwrap();
+        fs::write(base_path.join("top"), b"").unwrap();
+        fs::write(base_path.join("a/b/nested"), b"").unwrap();
+
+        let mut keys: Vec<_> = scan_tree(&base_path, &KeyTemplate::default())
+            .unwrap()
+            .into_iter()
+            .map(|file| file.key)
+            .collect();
+        keys.sort();
+        assert_eq!(keys, vec!["base/a/b/nested", "base/top"]);
     }
 
     #[test]